@@ -0,0 +1,56 @@
+extern crate wren;
+
+use wren::{wren_class, Configuration, WrenForeign, VM};
+
+#[derive(WrenForeign)]
+pub struct Vec3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+#[wren_class]
+impl Vec3 {
+    #[wren(construct)]
+    pub fn new(x: f64, y: f64, z: f64) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    #[wren(getter, name = "toString")]
+    pub fn description(&self) -> String {
+        format!("{}, {}, {}", self.x, self.y, self.z)
+    }
+
+    pub fn norm(&self) -> f64 {
+        (self.x.powi(2) + self.y.powi(2) + self.z.powi(2)).sqrt()
+    }
+
+    pub fn dot(&self, rhs: &Vec3) -> f64 {
+        (self.x * rhs.x) + (self.y * rhs.y) + (self.z * rhs.z)
+    }
+
+    pub fn cross(&self, rhs: &Vec3) -> Vec3 {
+        Vec3::new((self.y * rhs.z) - (self.z * rhs.y),
+                  (self.z * rhs.x) - (self.x * rhs.z),
+                  (self.x * rhs.y) - (self.y * rhs.x))
+    }
+}
+
+fn main() {
+    let source = r#"
+import "vector" for Vec3
+var vec = Vec3.new(1, 2, 3)
+var vec2 = Vec3.new(45, 30, 15)
+vec2.z = 20
+System.print("vec = %(vec)")
+System.print("vec2 = %(vec2)")
+System.print("vec.norm() = %(vec.norm())")
+System.print("vec.dot(vec2) = %(vec.dot(vec2))")
+System.print("vec.cross(vec2) = %(vec.cross(vec2))")
+"#;
+    let mut cfg = Configuration::new();
+    cfg.bindings_mut().add_foreign_class::<Vec3>("vector");
+    cfg.bindings_mut().define_module("vector");
+    let mut vm = VM::new(cfg);
+    vm.interpret(source).unwrap();
+}
@@ -1,13 +1,9 @@
 #[macro_use]
 extern crate wren;
-#[macro_use]
-extern crate lazy_static;
 
-use std::mem;
-use std::ptr;
-use std::collections::HashMap;
-use wren::{Pointer, VM, Configuration, ForeignMethodFn, ForeignClassMethods};
+use wren::{VM, Configuration, ForeignClassMethods};
 
+#[derive(Clone, Copy)]
 pub struct Vec3 {
     pub x: f64,
     pub y: f64,
@@ -40,151 +36,66 @@ impl ToString for Vec3 {
     }
 }
 
-lazy_static! {
-    static ref FOREIGN_METHODS: HashMap<&'static str, ForeignMethodFn> = {
-        let mut map = HashMap::new();
-        map.insert("vectorVec3toString", wren_foreign_method_fn!(vec3_to_string));
-        map.insert("vectorVec3norm()", wren_foreign_method_fn!(vec3_norm));
-        map.insert("vectorVec3dot(_)", wren_foreign_method_fn!(vec3_dot));
-        map.insert("vectorVec3cross(_)", wren_foreign_method_fn!(vec3_cross));
-        map.insert("vectorVec3x", wren_foreign_method_fn!(vec3_get_x));
-        map.insert("vectorVec3x=(_)", wren_foreign_method_fn!(vec3_set_x));
-        map.insert("vectorVec3y", wren_foreign_method_fn!(vec3_get_y));
-        map.insert("vectorVec3y=(_)", wren_foreign_method_fn!(vec3_set_y));
-        map.insert("vectorVec3z", wren_foreign_method_fn!(vec3_get_z));
-        map.insert("vectorVec3z=(_)", wren_foreign_method_fn!(vec3_set_z));
-        map
-    };
-}
-
-lazy_static! {
-    static ref FOREIGN_CLASSES: HashMap<&'static str, ForeignClassMethods> = {
-        let mut map = HashMap::new();
-
-        let mut vec3_class_methods = ForeignClassMethods::new();
-        vec3_class_methods.set_allocate_fn(wren_foreign_method_fn!(vec3_allocate));
-        vec3_class_methods.set_finalize_fn(wren_finalizer_fn!(vec3_finalize));
-
-        map.insert("vectorVec3", vec3_class_methods);
-        map
-    };
-}
-
 fn vec3_allocate(vm: &mut VM) {
-    let ptr = vm.set_slot_new_foreign(0, 0, mem::size_of::<Vec3>()) as *mut Vec3;
     let vec = Vec3::new(vm.get_slot_double(1).unwrap(),
                         vm.get_slot_double(2).unwrap(),
                         vm.get_slot_double(3).unwrap());
-    unsafe { ptr::write(ptr, vec) };
-}
-
-fn vec3_finalize(_: Pointer) {
-    // do nothing.
+    vm.new_foreign(0, 0, vec);
 }
 
 fn vec3_to_string(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    vm.set_slot_string(0, unsafe { &(*vec).to_string() });
+    let string = vm.get_foreign::<Vec3>(0).unwrap().to_string();
+    vm.set_slot_string(0, &string);
 }
 
 fn vec3_norm(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    let result = unsafe { (*vec).norm() };
+    let result = vm.get_foreign::<Vec3>(0).unwrap().norm();
     vm.set_slot_double(0, result);
 }
 
 fn vec3_dot(vm: &mut VM) {
-    let lhs = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    let rhs = vm.get_slot_foreign(1).unwrap() as *mut Vec3;
-    let result = unsafe { (*lhs).dot(&*rhs) };
+    let rhs = *vm.get_foreign::<Vec3>(1).unwrap();
+    let result = vm.get_foreign::<Vec3>(0).unwrap().dot(&rhs);
     vm.set_slot_double(0, result);
 }
 
 fn vec3_cross(vm: &mut VM) {
-    let lhs = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    let rhs = vm.get_slot_foreign(1).unwrap() as *mut Vec3;
-    let result = unsafe { (*lhs).cross(&*rhs) };
+    let rhs = *vm.get_foreign::<Vec3>(1).unwrap();
+    let result = vm.get_foreign::<Vec3>(0).unwrap().cross(&rhs);
 
     // Retrieve the Vec3 class and create a new object.
-    vm.get_variable("vector", "Vec3", 0);
-    let result_ptr = vm.set_slot_new_foreign(0, 0, mem::size_of::<Vec3>()) as *mut Vec3;
-    unsafe { ptr::write(result_ptr, result) };
+    vm.get_variable("vector", "Vec3", 0).unwrap();
+    vm.new_foreign(0, 0, result);
 }
 
 fn vec3_get_x(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    let x = unsafe { (*vec).x };
+    let x = vm.get_foreign::<Vec3>(0).unwrap().x;
     vm.set_slot_double(0, x);
 }
 
 fn vec3_set_x(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
     let x = vm.get_slot_double(1).unwrap();
-    unsafe { (*vec).x = x };
+    vm.get_foreign::<Vec3>(0).unwrap().x = x;
 }
 
 fn vec3_get_y(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    let y = unsafe { (*vec).y };
+    let y = vm.get_foreign::<Vec3>(0).unwrap().y;
     vm.set_slot_double(0, y);
 }
 
 fn vec3_set_y(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
     let y = vm.get_slot_double(1).unwrap();
-    unsafe { (*vec).y = y };
+    vm.get_foreign::<Vec3>(0).unwrap().y = y;
 }
 
 fn vec3_get_z(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
-    let z = unsafe { (*vec).z };
+    let z = vm.get_foreign::<Vec3>(0).unwrap().z;
     vm.set_slot_double(0, z);
 }
 
 fn vec3_set_z(vm: &mut VM) {
-    let vec = vm.get_slot_foreign(0).unwrap() as *mut Vec3;
     let z = vm.get_slot_double(1).unwrap();
-    unsafe { (*vec).z = z };
-}
-
-fn bind_method(_: &mut VM,
-               module: &str,
-               class_name: &str,
-               is_static: bool,
-               signature: &str)
-               -> ForeignMethodFn {
-    let full_signature = format!("{}{}{}{}",
-                                 module,
-                                 class_name,
-                                 signature,
-                                 if is_static { "s" } else { "" });
-    *FOREIGN_METHODS.get::<str>(&full_signature).unwrap_or(&None)
-}
-
-fn bind_class(_: &mut VM, module: &str, class_name: &str) -> ForeignClassMethods {
-    let full_signature = format!("{}{}", module, class_name);
-    let methods = FOREIGN_CLASSES.get::<str>(&full_signature);
-    if let Some(methods) = methods {
-        return *methods;
-    }
-    panic!("Failed to bind foreign class");
-}
-
-fn load_module(_: &mut VM, name: &str) -> Option<String> {
-    use std::path::Path;
-    use std::fs::File;
-    use std::io::Read;
-
-    let mut path = Path::new("examples/scripts").join(&name);
-    path.set_extension("wren");
-    let mut buffer = String::new();
-    if File::open(path)
-           .map(|mut f| f.read_to_string(&mut buffer))
-           .is_ok() {
-        Some(buffer)
-    } else {
-        None
-    }
+    vm.get_foreign::<Vec3>(0).unwrap().z = z;
 }
 
 fn main() {
@@ -199,9 +110,28 @@ System.print("vec.dot(vec2) = %(vec.dot(vec2))")
 System.print("vec.cross(vec2) = %(vec.cross(vec2))")
 "#;
     let mut cfg = Configuration::new();
-    cfg.set_bind_foreign_method_fn(wren_bind_foreign_method_fn!(bind_method));
-    cfg.set_bind_foreign_class_fn(wren_bind_foreign_class_fn!(bind_class));
-    cfg.set_load_module_fn(wren_load_module_fn!(load_module));
+    {
+        let bindings = cfg.bindings_mut();
+
+        let mut vec3_class_methods = ForeignClassMethods::new();
+        vec3_class_methods.set_allocate_fn(wren_foreign_method_fn!(vec3_allocate));
+        bindings.add_class("vector", "Vec3", vec3_class_methods);
+        bindings.add_constructor("vector", "Vec3", "new(_,_,_)");
+
+        bindings.add_method("vector", "Vec3", "toString", false, wren_foreign_method_fn!(vec3_to_string));
+        bindings.add_method("vector", "Vec3", "norm()", false, wren_foreign_method_fn!(vec3_norm));
+        bindings.add_method("vector", "Vec3", "dot(_)", false, wren_foreign_method_fn!(vec3_dot));
+        bindings.add_method("vector", "Vec3", "cross(_)", false, wren_foreign_method_fn!(vec3_cross));
+        bindings.add_method("vector", "Vec3", "x", false, wren_foreign_method_fn!(vec3_get_x));
+        bindings.add_method("vector", "Vec3", "x=(_)", false, wren_foreign_method_fn!(vec3_set_x));
+        bindings.add_method("vector", "Vec3", "y", false, wren_foreign_method_fn!(vec3_get_y));
+        bindings.add_method("vector", "Vec3", "y=(_)", false, wren_foreign_method_fn!(vec3_set_y));
+        bindings.add_method("vector", "Vec3", "z", false, wren_foreign_method_fn!(vec3_get_z));
+        bindings.add_method("vector", "Vec3", "z=(_)", false, wren_foreign_method_fn!(vec3_set_z));
+
+        // Generate the declaration of Vec3 instead of loading it from a script.
+        bindings.define_module("vector");
+    }
     let mut vm = VM::new(cfg);
-    vm.interpret(source);
+    vm.interpret(source).unwrap();
 }
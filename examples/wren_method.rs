@@ -7,12 +7,13 @@ fn main() {
 
     vm.interpret_file("examples/scripts/test.wren").unwrap();
 
-    vm.get_variable("main", "Test", 0);
-    let class_handle = vm.get_slot_handle(0).unwrap();
+    // Calling a method by hand.
+    vm.get_variable("main", "Test", 0).unwrap();
+    let class_handle = vm.get_slot_handle(0);
 
-    let zero = vm.make_call_handle("zero()");
-    let one = vm.make_call_handle("one(_)");
-    let two = vm.make_call_handle("two(_,_)");
+    let zero = vm.make_call_handle("zero()").unwrap();
+    let one = vm.make_call_handle("one(_)").unwrap();
+    let two = vm.make_call_handle("two(_,_)").unwrap();
 
     vm.set_slot_handle(0, &class_handle);
     vm.call(&zero);
@@ -25,4 +26,9 @@ fn main() {
     vm.set_slot_double(1, 1.0);
     vm.set_slot_double(2, 2.0);
     vm.call(&two);
+
+    // The same calls with converted arguments.
+    let () = vm.call_method(("main", "Test"), "zero()", ()).unwrap();
+    let () = vm.call_method(&class_handle, "one(_)", (1,)).unwrap();
+    let () = vm.call_method(&class_handle, "two(_,_)", (1, "two")).unwrap();
 }
@@ -0,0 +1,323 @@
+use macros;
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+use {ForeignClassMethods, ForeignFn, ForeignMethodFn, WrenClass, VM};
+
+type Closure = Rc<dyn Fn(&mut VM)>;
+
+/// The maximum number of closures a `ForeignBindings` can hold.
+pub const MAX_CLOSURES: usize = 256;
+
+// Wren doesn't pass any data to foreign methods, so every closure is called through its own
+// trampoline, which looks up the closure by index in the bindings of the calling VM.
+fn call_closure(vm: &mut VM, index: usize) {
+    let f = vm.context().bindings.closures[index].clone();
+    f(vm)
+}
+
+fn trampoline<const HIGH: usize, const LOW: usize>() -> ForeignMethodFn {
+    macros::_wrap_foreign_method_fn(|vm: &mut VM| call_closure(vm, HIGH * 16 + LOW))
+}
+
+macro_rules! trampoline_row {
+    ($high:expr) => {
+        [
+            trampoline::<$high, 0>,
+            trampoline::<$high, 1>,
+            trampoline::<$high, 2>,
+            trampoline::<$high, 3>,
+            trampoline::<$high, 4>,
+            trampoline::<$high, 5>,
+            trampoline::<$high, 6>,
+            trampoline::<$high, 7>,
+            trampoline::<$high, 8>,
+            trampoline::<$high, 9>,
+            trampoline::<$high, 10>,
+            trampoline::<$high, 11>,
+            trampoline::<$high, 12>,
+            trampoline::<$high, 13>,
+            trampoline::<$high, 14>,
+            trampoline::<$high, 15>,
+        ]
+    };
+}
+
+static TRAMPOLINES: [[fn() -> ForeignMethodFn; 16]; 16] = [
+    trampoline_row!(0),
+    trampoline_row!(1),
+    trampoline_row!(2),
+    trampoline_row!(3),
+    trampoline_row!(4),
+    trampoline_row!(5),
+    trampoline_row!(6),
+    trampoline_row!(7),
+    trampoline_row!(8),
+    trampoline_row!(9),
+    trampoline_row!(10),
+    trampoline_row!(11),
+    trampoline_row!(12),
+    trampoline_row!(13),
+    trampoline_row!(14),
+    trampoline_row!(15),
+];
+
+/// Foreign methods and classes available to scripts.
+///
+/// Every `Configuration` has a set of bindings, which its default bind functions look up when
+/// Wren encounters a foreign declaration.
+///
+/// Wren binds a declaration while running it and bind functions can't fail, so a foreign class
+/// missing from the bindings is only reported when it's constructed, by aborting the fiber with
+/// a message naming the class.
+#[derive(Clone, Default)]
+pub struct ForeignBindings {
+    methods: HashMap<(String, String, String, bool), ForeignMethodFn>,
+    classes: HashMap<(String, String), ForeignClassMethods>,
+    constructors: HashMap<(String, String), Vec<String>>,
+    closures: Vec<Closure>,
+    class_modules: HashMap<TypeId, String>,
+    generated_modules: HashSet<String>,
+}
+
+impl ForeignBindings {
+    pub fn new() -> ForeignBindings {
+        ForeignBindings::default()
+    }
+
+    /// Register a foreign method.
+    pub fn add_method(
+        &mut self,
+        module: &str,
+        class_name: &str,
+        signature: &str,
+        is_static: bool,
+        f: ForeignMethodFn,
+    ) {
+        let key = (
+            module.to_string(),
+            class_name.to_string(),
+            signature.to_string(),
+            is_static,
+        );
+        self.methods.insert(key, f);
+    }
+
+    /// Register a function with converted arguments as a foreign method named `name`.
+    ///
+    /// This is a shorthand for `add_method` with `wren_signature!` and `wren_foreign_fn!`, except
+    /// that closures may capture state. Those are stored like the ones passed to `add_closure`.
+    ///
+    /// Panics if a closure that captures state is passed and the bindings already hold
+    /// `MAX_CLOSURES` closures.
+    pub fn add_fn<Args: 'static, F: ForeignFn<Args> + 'static>(
+        &mut self,
+        module: &str,
+        class_name: &str,
+        name: &str,
+        is_static: bool,
+        f: F,
+    ) {
+        let signature = macros::_signature(name, &f);
+        // Functions that capture state can't be called through a function pointer alone.
+        let f = if mem::size_of::<F>() == 0 {
+            macros::foreign_fn(f)
+        } else {
+            self.closure_fn(move |vm: &mut VM| macros::call_foreign_fn(vm, &f))
+        };
+        self.add_method(module, class_name, &signature, is_static, f);
+    }
+
+    /// Register a closure as a foreign method.
+    ///
+    /// Unlike functions wrapped with `wren_foreign_method_fn!`, closures can capture state. Each VM
+    /// created from a configuration gets its own copy of the bindings, but clones of the closures
+    /// themselves are shared.
+    pub fn add_closure<F: Fn(&mut VM) + 'static>(
+        &mut self,
+        module: &str,
+        class_name: &str,
+        signature: &str,
+        is_static: bool,
+        f: F,
+    ) {
+        let f = self.closure_fn(f);
+        self.add_method(module, class_name, signature, is_static, f);
+    }
+
+    /// Store a closure and return a `ForeignMethodFn` that calls it, e.g. as a class allocator.
+    ///
+    /// The function may only be used with VMs created from these bindings.
+    ///
+    /// Panics if the bindings already hold `MAX_CLOSURES` closures.
+    pub fn closure_fn<F: Fn(&mut VM) + 'static>(&mut self, f: F) -> ForeignMethodFn {
+        let index = self.closures.len();
+        assert!(index < MAX_CLOSURES, "Too many foreign closures");
+        self.closures.push(Rc::new(f));
+        TRAMPOLINES[index / 16][index % 16]()
+    }
+
+    pub(crate) fn closure_count(&self) -> usize {
+        self.closures.len()
+    }
+
+    /// Register a foreign class.
+    pub fn add_class(&mut self, module: &str, class_name: &str, methods: ForeignClassMethods) {
+        let key = (module.to_string(), class_name.to_string());
+        self.classes.insert(key, methods);
+    }
+
+    /// Declare a constructor of a class, e.g. `new(_,_)`, for the generated Wren source.
+    ///
+    /// Constructors of foreign classes all call the allocator of the class.
+    pub fn add_constructor(&mut self, module: &str, class_name: &str, signature: &str) {
+        let key = (module.to_string(), class_name.to_string());
+        let constructors = self.constructors.entry(key).or_default();
+        if !constructors.iter().any(|c| c == signature) {
+            constructors.push(signature.to_string());
+        }
+    }
+
+    /// Register a foreign class implemented by a Rust type, usually with `#[wren_class]`.
+    pub fn add_foreign_class<T: WrenClass>(&mut self, module: &str) {
+        T::bind(module, self);
+        self.class_modules
+            .insert(TypeId::of::<T>(), module.to_string());
+    }
+
+    /// Look up the module a Rust type was registered in with `add_foreign_class`.
+    pub fn foreign_class_module<T: WrenClass>(&self) -> Option<&str> {
+        self.class_modules
+            .get(&TypeId::of::<T>())
+            .map(|m| m.as_str())
+    }
+
+    /// Look up a foreign method, returning `None` if it isn't registered.
+    pub fn method(
+        &self,
+        module: &str,
+        class_name: &str,
+        signature: &str,
+        is_static: bool,
+    ) -> ForeignMethodFn {
+        let key = (
+            module.to_string(),
+            class_name.to_string(),
+            signature.to_string(),
+            is_static,
+        );
+        self.methods.get(&key).cloned().unwrap_or(None)
+    }
+
+    /// Look up a foreign class.
+    pub fn class(&self, module: &str, class_name: &str) -> Option<ForeignClassMethods> {
+        let key = (module.to_string(), class_name.to_string());
+        self.classes.get(&key).cloned()
+    }
+
+    /// Mark a module as entirely defined by these bindings.
+    ///
+    /// Importing the module then loads the source returned by `module_source` instead of asking
+    /// the load module function. This only works with load module functions wrapped by
+    /// `wren_load_module_fn!`, including the default one.
+    pub fn define_module(&mut self, module: &str) {
+        self.generated_modules.insert(module.to_string());
+    }
+
+    /// Get the source of a module marked with `define_module`.
+    pub(crate) fn defined_module_source(&self, module: &str) -> Option<String> {
+        if self.generated_modules.contains(module) {
+            self.module_source(module)
+        } else {
+            None
+        }
+    }
+
+    /// Generate the Wren declaration of a class from its registered methods and constructors.
+    ///
+    /// Returns `None` if nothing is registered for the class.
+    pub fn class_source(&self, module: &str, class_name: &str) -> Option<String> {
+        let key = (module.to_string(), class_name.to_string());
+        let mut methods: Vec<(bool, &str)> = self
+            .methods
+            .keys()
+            .filter(|m| m.0 == module && m.1 == class_name)
+            .map(|m| (!m.3, m.2.as_str()))
+            .collect();
+        let constructors = self.constructors.get(&key);
+        let is_foreign = self.classes.contains_key(&key);
+        if methods.is_empty() && constructors.is_none() && !is_foreign {
+            return None;
+        }
+        // Static methods first, then by signature.
+        methods.sort();
+
+        let mut source = String::new();
+        if is_foreign {
+            source.push_str("foreign ");
+        }
+        source.push_str(&format!("class {} {{\n", class_name));
+        for signature in constructors.into_iter().flatten() {
+            source.push_str(&format!("  construct {} {{}}\n", declaration(signature)));
+        }
+        for (is_instance, signature) in methods {
+            let modifier = if is_instance { "" } else { "static " };
+            source.push_str(&format!(
+                "  foreign {}{}\n",
+                modifier,
+                declaration(signature)
+            ));
+        }
+        source.push_str("}\n");
+        Some(source)
+    }
+
+    /// Generate the Wren declarations of all classes registered in a module.
+    ///
+    /// Returns `None` if nothing is registered in the module.
+    pub fn module_source(&self, module: &str) -> Option<String> {
+        let mut classes: Vec<&str> = self
+            .methods
+            .keys()
+            .map(|m| (&m.0, &m.1))
+            .chain(self.classes.keys().map(|c| (&c.0, &c.1)))
+            .chain(self.constructors.keys().map(|c| (&c.0, &c.1)))
+            .filter(|c| c.0 == module)
+            .map(|c| c.1.as_str())
+            .collect();
+        if classes.is_empty() {
+            return None;
+        }
+        classes.sort();
+        classes.dedup();
+        let classes: Vec<String> = classes
+            .into_iter()
+            .filter_map(|c| self.class_source(module, c))
+            .collect();
+        Some(classes.join("\n"))
+    }
+}
+
+/// Turn a signature like `name(_,_)` or `[_]=(_)` into a declaration with named parameters.
+fn declaration(signature: &str) -> String {
+    let mut declaration = String::new();
+    let mut params = 0;
+    let mut depth = 0;
+    for c in signature.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ => (),
+        }
+        if depth > 0 && c == '_' {
+            declaration.push((b'a' + params) as char);
+            params += 1;
+        } else if depth > 0 && c == ',' {
+            declaration.push_str(", ");
+        } else {
+            declaration.push(c);
+        }
+    }
+    declaration
+}
@@ -0,0 +1,117 @@
+use vm::signature_arity;
+use {Error, FromWren, Handle, ToWren, VM};
+
+/// The receiver of a method called with `VM::call_method`.
+pub trait Receiver {
+    /// Store the receiver in `slot`.
+    fn set_receiver(&self, vm: &mut VM, slot: i32) -> Result<(), Error>;
+}
+
+impl<T: Receiver + ?Sized> Receiver for &T {
+    fn set_receiver(&self, vm: &mut VM, slot: i32) -> Result<(), Error> {
+        (**self).set_receiver(vm, slot)
+    }
+}
+
+impl Receiver for Handle {
+    fn set_receiver(&self, vm: &mut VM, slot: i32) -> Result<(), Error> {
+        vm.set_slot_handle(slot, self);
+        Ok(())
+    }
+}
+
+/// A top-level variable, given as `(module, name)`.
+impl Receiver for (&str, &str) {
+    fn set_receiver(&self, vm: &mut VM, slot: i32) -> Result<(), Error> {
+        vm.get_variable(self.0, self.1, slot)
+    }
+}
+
+/// The arguments of a method called with `VM::call_method`, as a tuple of `ToWren` values.
+pub trait WrenArgs {
+    const COUNT: usize;
+
+    /// Store the arguments in the slots starting at `first_slot`.
+    fn set_args(&self, vm: &mut VM, first_slot: i32);
+}
+
+macro_rules! impl_wren_args {
+    ($count:expr => $($name:ident $index:tt)*) => {
+        impl<$($name: ToWren),*> WrenArgs for ($($name,)*) {
+            const COUNT: usize = $count;
+
+            #[allow(unused_variables)]
+            fn set_args(&self, vm: &mut VM, first_slot: i32) {
+                $(self.$index.to_wren(vm, first_slot + $index);)*
+            }
+        }
+    };
+}
+
+impl_wren_args!(0 =>);
+impl_wren_args!(1 => A 0);
+impl_wren_args!(2 => A 0 B 1);
+impl_wren_args!(3 => A 0 B 1 C 2);
+impl_wren_args!(4 => A 0 B 1 C 2 D 3);
+impl_wren_args!(5 => A 0 B 1 C 2 D 3 E 4);
+impl_wren_args!(6 => A 0 B 1 C 2 D 3 E 4 F 5);
+impl_wren_args!(7 => A 0 B 1 C 2 D 3 E 4 F 5 G 6);
+impl_wren_args!(8 => A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7);
+
+/// A method bound to its receiver, e.g. a Wren `Fn` stored as a callback.
+///
+/// The function owns handles to the receiver and the method, so it can be called repeatedly
+/// without looking either up again.
+#[derive(Clone, Debug)]
+pub struct WrenFunction {
+    receiver: Handle,
+    method: Handle,
+    signature: String,
+}
+
+impl WrenFunction {
+    /// Bind the method with `signature` to `receiver`.
+    #[track_caller]
+    pub fn new(vm: &mut VM, receiver: Handle, signature: &str) -> Result<WrenFunction, Error> {
+        let method = vm.call_handle(signature)?;
+        Ok(WrenFunction {
+            receiver,
+            method,
+            signature: signature.to_string(),
+        })
+    }
+
+    /// Bind the `call` method of a Wren `Fn`, with as many parameters as the function.
+    ///
+    /// Returns an error if the value doesn't have an `arity`, like `Fn` does.
+    #[track_caller]
+    pub fn from_fn(vm: &mut VM, function: Handle) -> Result<WrenFunction, Error> {
+        let arity: usize = vm.call_method(&function, "arity", ())?;
+        let params = vec!["_"; arity];
+        let signature = format!("call({})", params.join(","));
+        WrenFunction::new(vm, function, &signature)
+    }
+
+    pub fn receiver(&self) -> &Handle {
+        &self.receiver
+    }
+
+    pub fn signature(&self) -> &str {
+        &self.signature
+    }
+
+    /// Call the method like `VM::call_method`.
+    ///
+    /// Must not be used inside foreign methods. Panics if the number of arguments doesn't match
+    /// the signature.
+    #[track_caller]
+    pub fn call<A: WrenArgs, R: FromWren>(&self, vm: &mut VM, args: A) -> Result<R, Error> {
+        assert!(
+            signature_arity(&self.signature) == A::COUNT,
+            "Expected {} arguments for {}",
+            signature_arity(&self.signature),
+            self.signature
+        );
+        vm.call_with(&self.receiver, &self.method, args)
+    }
+}
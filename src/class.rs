@@ -0,0 +1,23 @@
+use ForeignBindings;
+
+/// A Rust type whose instances can be stored in Wren as foreign objects.
+///
+/// This is usually implemented with `#[derive(WrenForeign)]`, which also binds getters and
+/// setters for the public fields of a struct.
+pub trait WrenForeign: Sized + 'static {
+    /// The name of the class in Wren.
+    const NAME: &'static str;
+
+    /// Register the field getters and setters of the class.
+    fn bind_fields(module: &str, bindings: &mut ForeignBindings);
+}
+
+/// A foreign class backed by a Rust type.
+///
+/// This is usually implemented by putting `#[wren_class]` on an `impl` block, which binds its
+/// methods along with an allocator and finalizer for the type. Register the class with
+/// `ForeignBindings::add_foreign_class`.
+pub trait WrenClass: WrenForeign {
+    /// Register the class with its allocator, finalizer, fields and methods.
+    fn bind(module: &str, bindings: &mut ForeignBindings);
+}
@@ -0,0 +1,367 @@
+use std::any::type_name;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use {Error, Handle, Type, Value, VM};
+
+/// Conversion from a value stored in a slot.
+///
+/// Implemented for `()`, `bool`, floats, integers, strings, `Option`, `Vec`, tuples, `Value` and
+/// `Handle`, with two gaps:
+///
+/// - `u8` isn't converted from a number, so that `Vec<u8>` can hold the bytes of a string. Read
+///   small numbers as a wider integer type instead.
+/// - Maps can't be converted, as Wren's embedding API has no way to enumerate their keys. Read
+///   them as a `Value` or `Handle` to keep a reference instead.
+///
+/// See `VM::get`.
+pub trait FromWren: Sized {
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<Self, Error>;
+}
+
+/// Conversion into a value stored in a slot.
+///
+/// Implemented for the same types as `FromWren`, as well as `HashMap` and `BTreeMap`. `u8` isn't
+/// converted to a number, and `Vec<u8>` and `[u8]` become strings.
+///
+/// See `VM::set`.
+pub trait ToWren {
+    fn to_wren(&self, vm: &mut VM, slot: i32);
+}
+
+fn check_type(vm: &mut VM, slot: i32, expected: Type) -> Result<(), Error> {
+    let found = vm.get_slot_type(slot);
+    if found == expected {
+        Ok(())
+    } else {
+        Err(Error::WrongType { expected, found })
+    }
+}
+
+impl<T: ToWren + ?Sized> ToWren for &T {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        (**self).to_wren(vm, slot)
+    }
+}
+
+impl FromWren for () {
+    /// Accepts any value, which makes it useful for ignoring results.
+    fn from_wren(_: &mut VM, _: i32) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ToWren for () {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_null(slot)
+    }
+}
+
+impl FromWren for bool {
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<bool, Error> {
+        check_type(vm, slot, Type::Bool)?;
+        Ok(vm.get_slot_bool(slot).unwrap())
+    }
+}
+
+impl ToWren for bool {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_bool(slot, *self)
+    }
+}
+
+impl FromWren for f64 {
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<f64, Error> {
+        check_type(vm, slot, Type::Num)?;
+        Ok(vm.get_slot_double(slot).unwrap())
+    }
+}
+
+impl ToWren for f64 {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_double(slot, *self)
+    }
+}
+
+impl FromWren for f32 {
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<f32, Error> {
+        f64::from_wren(vm, slot).map(|n| n as f32)
+    }
+}
+
+impl ToWren for f32 {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_double(slot, f64::from(*self))
+    }
+}
+
+// Integers must be integral and in range, so that converting never silently changes the value.
+//
+// `u8` is deliberately left out so that `Vec<u8>` can be converted to and from a string of bytes.
+macro_rules! impl_integer {
+    ($($t:ident)*) => {
+        $(
+            impl FromWren for $t {
+                fn from_wren(vm: &mut VM, slot: i32) -> Result<$t, Error> {
+                    let n = f64::from_wren(vm, slot)?;
+                    // `MAX as f64` may round up to the next power of two, which is still out of range.
+                    if n.fract() == 0.0 && n >= $t::MIN as f64 && n < $t::MAX as f64 + 1.0 {
+                        Ok(n as $t)
+                    } else {
+                        Err(Error::OutOfRange {
+                            expected: stringify!($t),
+                            value: n,
+                        })
+                    }
+                }
+            }
+
+            impl ToWren for $t {
+                fn to_wren(&self, vm: &mut VM, slot: i32) {
+                    vm.set_slot_double(slot, *self as f64)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(i8 i16 i32 i64 isize u16 u32 u64 usize);
+
+impl FromWren for String {
+    /// Strings that aren't valid UTF-8 are converted lossily. Use `Vec<u8>` to get the raw bytes.
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<String, Error> {
+        check_type(vm, slot, Type::String)?;
+        let bytes = vm.get_slot_bytes(slot).unwrap();
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+impl ToWren for String {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_string(slot, self)
+    }
+}
+
+impl ToWren for str {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_string(slot, self)
+    }
+}
+
+impl FromWren for Vec<u8> {
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<Vec<u8>, Error> {
+        check_type(vm, slot, Type::String)?;
+        Ok(vm.get_slot_bytes(slot).unwrap().to_vec())
+    }
+}
+
+impl ToWren for Vec<u8> {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_bytes(slot, self)
+    }
+}
+
+impl ToWren for [u8] {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_bytes(slot, self)
+    }
+}
+
+impl<T: FromWren> FromWren for Option<T> {
+    /// Null is converted to `None`.
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<Option<T>, Error> {
+        if vm.get_slot_type(slot) == Type::Null {
+            Ok(None)
+        } else {
+            T::from_wren(vm, slot).map(Some)
+        }
+    }
+}
+
+impl<T: ToWren> ToWren for Option<T> {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        match *self {
+            Some(ref value) => value.to_wren(vm, slot),
+            None => vm.set_slot_null(slot),
+        }
+    }
+}
+
+impl<T: FromWren> FromWren for Vec<T> {
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<Vec<T>, Error> {
+        check_type(vm, slot, Type::List)?;
+        let element_slot = vm.get_slot_count();
+        (0..vm.get_list_count(slot))
+            .map(|index| {
+                vm.get_list_element(slot, index, element_slot);
+                T::from_wren(vm, element_slot)
+            })
+            .collect()
+    }
+}
+
+impl<T: ToWren> ToWren for Vec<T> {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        self[..].to_wren(vm, slot)
+    }
+}
+
+impl<T: ToWren> ToWren for [T] {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_new_list(slot);
+        let element_slot = vm.get_slot_count();
+        for element in self {
+            element.to_wren(vm, element_slot);
+            vm.insert_in_list(slot, -1, element_slot);
+        }
+    }
+}
+
+// Maps can only be converted to Wren, see `FromWren`.
+fn set_new_map<'a, K, V, I>(vm: &mut VM, slot: i32, entries: I)
+where
+    K: ToWren + 'a,
+    V: ToWren + 'a,
+    I: Iterator<Item = (&'a K, &'a V)>,
+{
+    vm.set_slot_new_map(slot);
+    let key_slot = vm.get_slot_count();
+    for (key, value) in entries {
+        key.to_wren(vm, key_slot);
+        value.to_wren(vm, key_slot + 1);
+        vm.set_map_value(slot, key_slot, key_slot + 1);
+    }
+}
+
+impl<K: ToWren + Eq + Hash, V: ToWren, S: BuildHasher> ToWren for HashMap<K, V, S> {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        set_new_map(vm, slot, self.iter())
+    }
+}
+
+impl<K: ToWren + Ord, V: ToWren> ToWren for BTreeMap<K, V> {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        set_new_map(vm, slot, self.iter())
+    }
+}
+
+// Tuples are converted to and from lists of the same length.
+macro_rules! impl_tuple {
+    ($len:expr => $($name:ident $index:tt)+) => {
+        impl<$($name: FromWren),+> FromWren for ($($name,)+) {
+            fn from_wren(vm: &mut VM, slot: i32) -> Result<($($name,)+), Error> {
+                check_type(vm, slot, Type::List)?;
+                let count = vm.get_list_count(slot) as usize;
+                if count != $len {
+                    return Err(Error::WrongLength {
+                        expected: $len,
+                        found: count,
+                    });
+                }
+                let element_slot = vm.get_slot_count();
+                Ok(($({
+                    vm.get_list_element(slot, $index, element_slot);
+                    $name::from_wren(vm, element_slot)?
+                },)+))
+            }
+        }
+
+        impl<$($name: ToWren),+> ToWren for ($($name,)+) {
+            fn to_wren(&self, vm: &mut VM, slot: i32) {
+                vm.set_slot_new_list(slot);
+                let element_slot = vm.get_slot_count();
+                $(
+                    self.$index.to_wren(vm, element_slot);
+                    vm.insert_in_list(slot, -1, element_slot);
+                )+
+            }
+        }
+    };
+}
+
+impl_tuple!(1 => A 0);
+impl_tuple!(2 => A 0 B 1);
+impl_tuple!(3 => A 0 B 1 C 2);
+impl_tuple!(4 => A 0 B 1 C 2 D 3);
+impl_tuple!(5 => A 0 B 1 C 2 D 3 E 4);
+impl_tuple!(6 => A 0 B 1 C 2 D 3 E 4 F 5);
+
+impl FromWren for Value {
+    #[track_caller]
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<Value, Error> {
+        Ok(vm.get_slot(slot))
+    }
+}
+
+impl ToWren for Value {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot(slot, self)
+    }
+}
+
+impl FromWren for Handle {
+    #[track_caller]
+    fn from_wren(vm: &mut VM, slot: i32) -> Result<Handle, Error> {
+        Ok(vm.get_slot_handle(slot))
+    }
+}
+
+impl ToWren for Handle {
+    fn to_wren(&self, vm: &mut VM, slot: i32) {
+        vm.set_slot_handle(slot, self)
+    }
+}
+
+/// A Rust function that can be called as a foreign method.
+///
+/// This is implemented for functions and closures with up to 8 `FromWren` arguments that return
+/// a `ToWren` value. Arguments are read from slots 1 and up, and the result is stored in slot 0.
+/// Use `wren_foreign_fn!` to wrap one as a `ForeignMethodFn`, or `ForeignBindings::add_fn` to
+/// register it. Only `add_fn` accepts closures that capture state.
+pub trait ForeignFn<Args> {
+    /// The number of arguments the function takes.
+    const ARITY: usize;
+
+    /// Converts the arguments, calls the function and stores the result.
+    ///
+    /// Returns a message describing the problem if an argument can't be converted.
+    fn invoke(&self, vm: &mut VM) -> Result<(), String>;
+}
+
+macro_rules! impl_foreign_fn {
+    ($arity:expr => $($arg:ident $slot:expr),*) => {
+        impl<Func, Ret, $($arg),*> ForeignFn<($($arg,)*)> for Func
+        where
+            Func: Fn($($arg),*) -> Ret,
+            Ret: ToWren,
+            $($arg: FromWren,)*
+        {
+            const ARITY: usize = $arity;
+
+            #[allow(unused_variables)]
+            fn invoke(&self, vm: &mut VM) -> Result<(), String> {
+                let result = self($(
+                    $arg::from_wren(vm, $slot).map_err(|e| {
+                        format!(
+                            "Invalid argument {} for {}: {}",
+                            $slot,
+                            type_name::<Func>(),
+                            e
+                        )
+                    })?
+                ),*);
+                result.to_wren(vm, 0);
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_foreign_fn!(0 =>);
+impl_foreign_fn!(1 => A 1);
+impl_foreign_fn!(2 => A 1, B 2);
+impl_foreign_fn!(3 => A 1, B 2, C 3);
+impl_foreign_fn!(4 => A 1, B 2, C 3, D 4);
+impl_foreign_fn!(5 => A 1, B 2, C 3, D 4, E 5);
+impl_foreign_fn!(6 => A 1, B 2, C 3, D 4, E 5, F 6);
+impl_foreign_fn!(7 => A 1, B 2, C 3, D 4, E 5, F 6, G 7);
+impl_foreign_fn!(8 => A 1, B 2, C 3, D 4, E 5, F 6, G 7, H 8);
@@ -0,0 +1,172 @@
+use std::error;
+use std::fmt;
+use {ErrorType, InterpretResult, Report, Type};
+
+/// Error type for fallible `VM` functions.
+#[derive(Debug)]
+pub enum Error {
+    /// No module with this name has been loaded.
+    UnknownModule(String),
+    /// The module doesn't define a top-level variable with this name.
+    UnknownVariable { module: String, name: String },
+    /// A slot contains a different type of value than expected.
+    WrongType { expected: Type, found: Type },
+    /// A number can't be represented by the expected integer type.
+    OutOfRange { expected: &'static str, value: f64 },
+    /// A list doesn't have the expected number of elements.
+    WrongLength { expected: usize, found: usize },
+    /// A string passed to Wren contains a NUL byte at this position.
+    NulByte(usize),
+    /// Running Wren code failed.
+    Wren(WrenError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownModule(ref module) => write!(f, "Could not find module '{}'", module),
+            Error::UnknownVariable {
+                ref module,
+                ref name,
+            } => write!(
+                f,
+                "Could not find variable '{}' in module '{}'",
+                name, module
+            ),
+            Error::WrongType { expected, found } => {
+                write!(f, "Expected {:?}, found {:?}", expected, found)
+            }
+            Error::OutOfRange { expected, value } => {
+                write!(f, "Expected {}, found {}", expected, value)
+            }
+            Error::WrongLength { expected, found } => write!(
+                f,
+                "Expected a list of {} elements, found {}",
+                expected, found
+            ),
+            Error::NulByte(position) => write!(f, "Unexpected NUL byte at position {}", position),
+            Error::Wren(ref error) => error.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<WrenError> for Error {
+    fn from(error: WrenError) -> Error {
+        Error::Wren(error)
+    }
+}
+
+/// A compile error reported by Wren.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub module: String,
+    pub line: i32,
+    pub message: String,
+}
+
+/// A function in the stack trace of a runtime error, innermost first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    pub module: String,
+    pub line: i32,
+    pub function: String,
+}
+
+/// Error type for running Wren code with `VM::run` and `VM::try_call`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WrenError {
+    /// The source failed to compile.
+    Compile(Vec<CompileError>),
+    /// A fiber was aborted.
+    Runtime {
+        message: String,
+        stack_trace: Vec<StackFrame>,
+    },
+    /// The source of a module contains a NUL byte at this position, so Wren can't compile it.
+    NulByte { module: String, position: usize },
+}
+
+impl WrenError {
+    // Build the error from the messages reported while running.
+    pub(crate) fn from_reports(result: InterpretResult, reports: Vec<Report>) -> Result<(), Self> {
+        match result {
+            InterpretResult::Success => Ok(()),
+            InterpretResult::CompileError => Err(WrenError::Compile(
+                reports
+                    .into_iter()
+                    .filter(|r| r.error_type == ErrorType::Compile)
+                    .map(|r| CompileError {
+                        module: r.module,
+                        line: r.line,
+                        message: r.message,
+                    })
+                    .collect(),
+            )),
+            InterpretResult::RuntimeError => {
+                let mut message = String::new();
+                let mut stack_trace = Vec::new();
+                for report in reports {
+                    match report.error_type {
+                        ErrorType::Runtime => message = report.message,
+                        ErrorType::StackTrace => stack_trace.push(StackFrame {
+                            module: report.module,
+                            line: report.line,
+                            function: report.message,
+                        }),
+                        ErrorType::Compile => {}
+                    }
+                }
+                Err(WrenError::Runtime {
+                    message,
+                    stack_trace,
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for WrenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WrenError::Compile(ref errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(
+                        f,
+                        "[{} line {}] {}",
+                        error.module, error.line, error.message
+                    )?;
+                }
+                Ok(())
+            }
+            WrenError::Runtime {
+                ref message,
+                ref stack_trace,
+            } => {
+                write!(f, "{}", message)?;
+                for frame in stack_trace {
+                    write!(
+                        f,
+                        "\n[{} line {}] in {}",
+                        frame.module, frame.line, frame.function
+                    )?;
+                }
+                Ok(())
+            }
+            WrenError::NulByte {
+                ref module,
+                position,
+            } => write!(
+                f,
+                "Unexpected NUL byte in module '{}' at position {}",
+                module, position
+            ),
+        }
+    }
+}
+
+impl error::Error for WrenError {}
@@ -0,0 +1,137 @@
+use libc::c_void;
+use macros;
+use std::any::TypeId;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+use Pointer;
+
+// Every foreign object created through `VM` starts with a header, which records the Rust type
+// stored in it, if any. Pointers handed out to users point past the header.
+#[repr(C)]
+struct Header {
+    // Always `MAGIC`, checked before trusting the rest of the header.
+    magic: u64,
+    type_id: Option<TypeId>,
+    drop: Option<unsafe fn(Pointer)>,
+}
+
+const MAGIC: u64 = 0x7772_656e_2d72_7321;
+
+/// The size of the header, which keeps the data 8-byte aligned.
+pub(crate) const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+// Foreign classes can also be implemented in C, like `Random` in the optional `random` module, so
+// objects are recorded when their header is written and only recorded objects are assumed to have
+// one. They're forgotten when Wren frees their memory, which goes through `VM`'s reallocate
+// function whatever the finalizer of their class is. Neither gets the VM, so the record can't
+// live in it.
+thread_local! {
+    // The memory block of each recorded object, by the address of its header.
+    static OBJECTS: RefCell<HashMap<usize, usize>> = RefCell::new(HashMap::new());
+    // The recorded objects, by the address of their memory block.
+    static BLOCKS: RefCell<HashMap<usize, usize>> = RefCell::new(HashMap::new());
+    // The block returned by the last allocation, which holds the object `wrenSetSlotNewForeign`
+    // just created.
+    static LAST_ALLOCATION: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Called with the memory returned by every allocation of a VM.
+pub(crate) fn allocated(block: Pointer) {
+    LAST_ALLOCATION.with(|last| last.set(block as usize));
+}
+
+/// Called with the memory passed to every reallocation or free of a VM.
+pub(crate) fn freed(block: Pointer) {
+    let _ = BLOCKS.try_with(|blocks| {
+        let raw = match blocks.borrow_mut().remove(&(block as usize)) {
+            Some(raw) => raw,
+            None => return,
+        };
+        OBJECTS.with(|objects| objects.borrow_mut().remove(&raw));
+    });
+}
+
+/// Write the header of an untyped object and return a pointer to its data.
+pub(crate) unsafe fn init_untyped(raw: Pointer) -> Pointer {
+    write_header(raw, None, None);
+    skip_header(raw)
+}
+
+/// Move `value` into a new object with a header recording its type.
+pub(crate) unsafe fn init_typed<T: 'static>(raw: Pointer, value: T) -> *mut T {
+    unsafe fn drop_value<T>(data: Pointer) {
+        ptr::drop_in_place(data as *mut T)
+    }
+
+    let data = skip_header(raw) as *mut T;
+    assert!(
+        data as usize & (mem::align_of::<T>() - 1) == 0,
+        "Foreign values must not be aligned to more than 8 bytes"
+    );
+    ptr::write(data, value);
+    write_header(raw, Some(TypeId::of::<T>()), Some(drop_value::<T>));
+    data
+}
+
+unsafe fn write_header(raw: Pointer, type_id: Option<TypeId>, drop: Option<unsafe fn(Pointer)>) {
+    ptr::write(
+        raw as *mut Header,
+        Header {
+            magic: MAGIC,
+            type_id,
+            drop,
+        },
+    );
+    let block = LAST_ALLOCATION.with(Cell::get);
+    assert!(
+        block != 0 && block < raw as usize,
+        "Foreign objects must be allocated by the VM"
+    );
+    OBJECTS.with(|objects| objects.borrow_mut().insert(raw as usize, block));
+    BLOCKS.with(|blocks| blocks.borrow_mut().insert(block, raw as usize));
+}
+
+fn skip_header(raw: Pointer) -> Pointer {
+    (raw as usize + HEADER_SIZE) as Pointer
+}
+
+/// Get the header of an object, or `None` if it wasn't created through `VM`.
+unsafe fn header<'a>(raw: Pointer) -> Option<&'a mut Header> {
+    let recorded = OBJECTS
+        .try_with(|objects| objects.borrow().contains_key(&(raw as usize)))
+        .unwrap_or(false);
+    if !recorded {
+        return None;
+    }
+    let header = &mut *(raw as *mut Header);
+    if header.magic == MAGIC {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+/// Get the data of an object. Objects created outside of this crate have no header.
+pub(crate) fn data(raw: Pointer) -> Pointer {
+    if unsafe { header(raw) }.is_some() {
+        skip_header(raw)
+    } else {
+        raw
+    }
+}
+
+/// Get the type stored in an object, or `None` if it's untyped or wasn't created through `VM`.
+pub(crate) unsafe fn type_id(raw: Pointer) -> Option<TypeId> {
+    header(raw).and_then(|header| header.type_id)
+}
+
+/// The default finalizer of foreign classes, which drops typed values.
+///
+/// Panics while dropping abort the process, as there's no VM to store them in.
+pub(crate) unsafe extern "C" fn finalize(raw: *mut c_void) {
+    if let Some(drop) = header(raw).and_then(|header| header.drop.take()) {
+        macros::abort_on_panic(|| drop(skip_header(raw)));
+    }
+}
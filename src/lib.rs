@@ -3,10 +3,27 @@
 //! For complete documentation on each type and function, refer to `wren.h` in the official Wren repository.
 
 extern crate libc;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "derive")]
+extern crate wren_derive;
 extern crate wren_sys as ffi;
 
+// Lets the code generated by `wren-derive` refer to `::wren` in the tests of this crate.
+#[cfg(all(test, feature = "derive"))]
+extern crate self as wren;
+
 #[macro_use]
 pub mod macros;
+mod bindings;
+mod call;
+mod class;
+mod convert;
+mod error;
+mod foreign;
+mod output;
+mod report;
+mod value;
 mod vm;
 
 /// Typedef for a raw pointer.
@@ -23,12 +40,24 @@ pub use ffi::WrenFinalizerFn as FinalizerFn;
 pub use ffi::WrenForeignMethodFn as ForeignMethodFn;
 pub use ffi::WrenLoadModuleFn as LoadModuleFn;
 pub use ffi::WrenReallocateFn as ReallocateFn;
+pub use ffi::WrenResolveModuleFn as ResolveModuleFn;
 pub use ffi::WrenWriteFn as WriteFn;
 
+pub use self::bindings::{ForeignBindings, MAX_CLOSURES};
+pub use self::call::{Receiver, WrenArgs, WrenFunction};
+pub use self::class::{WrenClass, WrenForeign};
+pub use self::convert::{ForeignFn, FromWren, ToWren};
+pub use self::error::{CompileError, Error, StackFrame, WrenError};
+pub use self::output::Output;
+pub use self::report::{CollectingReporter, LogReporter, Report, Reporter, StderrReporter};
+pub use self::value::Value;
+pub use self::vm::CallHandleStats;
 pub use self::vm::Configuration;
 pub use self::vm::ForeignClassMethods;
 pub use self::vm::Handle;
 pub use self::vm::VM;
+#[cfg(feature = "derive")]
+pub use wren_derive::{wren_class, WrenForeign};
 
 #[cfg(test)]
 mod tests;
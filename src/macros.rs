@@ -1,10 +1,19 @@
 use ffi;
+use foreign;
 use libc::*;
-use std::ffi::{CStr};
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::ptr;
 use ErrorType;
+use ForeignFn;
 use Pointer;
+use ToWren;
+use WrenClass;
+use WrenForeign;
 use VM;
 
 /// Wrap a `Fn(Pointer, usize) -> Pointer` as an ffi-suitable `ReallocateFn`.
@@ -23,7 +32,36 @@ macro_rules! wren_foreign_method_fn {
     };
 }
 
+/// Wrap a function with `FromWren` arguments and a `ToWren` result as an ffi-suitable
+/// `ForeignMethodFn`. See `ForeignFn`.
+///
+/// Closures that capture state fail to compile; register them with `ForeignBindings::add_fn`.
+///
+/// If an argument can't be converted, the fiber is aborted with a message naming the argument.
+#[macro_export]
+macro_rules! wren_foreign_fn {
+    ($f:path) => {
+        $crate::macros::_wrap_foreign_fn($f)
+    };
+}
+
+/// Build the Wren signature of a function wrapped with `wren_foreign_fn!`.
+///
+/// The method is named after the function unless a name is given, so `wren_signature!(sin)`
+/// and `wren_signature!("sin", math::sin)` both give `"sin(_)"` for `fn sin(x: f64) -> f64`.
+#[macro_export]
+macro_rules! wren_signature {
+    ($name:expr, $f:path) => {
+        $crate::macros::_signature($name, &$f)
+    };
+    ($f:path) => {
+        $crate::macros::_signature(stringify!($f).rsplit("::").next().unwrap().trim(), &$f)
+    };
+}
+
 /// Wrap a `Fn(Pointer)` as an ffi-suitable `FinalizerFn`.
+///
+/// Values of objects created with `VM::new_foreign` are dropped after the function returns.
 #[macro_export]
 macro_rules! wren_finalizer_fn {
     ($f:path) => {
@@ -47,7 +85,32 @@ macro_rules! wren_bind_foreign_class_fn {
     };
 }
 
+/// Wrap a `Fn(&mut VM, &str, &str) -> Option<String>` as an ffi-suitable `ResolveModuleFn`.
+///
+/// The function receives the name of the importing module and the imported name, and returns
+/// the canonical module name, or `None` if the import can't be resolved.
+#[macro_export]
+macro_rules! wren_resolve_module_fn {
+    ($f:path) => {
+        $crate::macros::_wrap_resolve_module_fn($f)
+    };
+}
+
+/// Wrap a `Fn(&mut VM, &str) -> Option<String>` as an ffi-suitable `LoadModuleFn`.
+///
+/// The function receives the name of the module to load and returns its source, or `None` if
+/// the module can't be found. Modules marked with `ForeignBindings::define_module` are loaded
+/// from the bindings without calling it.
+#[macro_export]
+macro_rules! wren_load_module_fn {
+    ($f:path) => {
+        $crate::macros::_wrap_load_module_fn($f)
+    };
+}
+
 /// Wrap a `Fn(&mut VM, &str)` as an ffi-suitable `WriteFn`.
+///
+/// Invalid UTF-8 in the text is replaced with `U+FFFD`.
 #[macro_export]
 macro_rules! wren_write_fn {
     ($f:path) => {
@@ -55,7 +118,17 @@ macro_rules! wren_write_fn {
     };
 }
 
+/// Wrap a `Fn(&mut VM, &[u8])` as an ffi-suitable `WriteFn`, which receives the raw text.
+#[macro_export]
+macro_rules! wren_write_bytes_fn {
+    ($f:path) => {
+        $crate::macros::_wrap_write_bytes_fn($f)
+    };
+}
+
 /// Wrap a `Fn(&mut VM, ErrorType, &str, i32, &str)` as an ffi-suitable `ErrorFn`.
+///
+/// Invalid UTF-8 in the module name and message is replaced with `U+FFFD`.
 #[macro_export]
 macro_rules! wren_error_fn {
     ($f:path) => {
@@ -63,6 +136,15 @@ macro_rules! wren_error_fn {
     };
 }
 
+/// Wrap a `Fn(&mut VM, ErrorType, &[u8], i32, &[u8])` as an ffi-suitable `ErrorFn`, which
+/// receives the raw module name and message.
+#[macro_export]
+macro_rules! wren_error_bytes_fn {
+    ($f:path) => {
+        $crate::macros::_wrap_error_bytes_fn($f)
+    };
+}
+
 #[doc(hidden)]
 #[inline]
 pub fn _default_realloc(memory: *mut c_void, new_size: usize) -> *mut c_void {
@@ -73,22 +155,58 @@ pub fn _default_realloc(memory: *mut c_void, new_size: usize) -> *mut c_void {
     unsafe { realloc(memory, new_size) }
 }
 
+// Panics must not unwind into Wren. Callbacks with a VM store the payload in it, the others
+// abort the process.
+fn catch<R, F: FnOnce(&mut VM) -> R>(vm: &mut VM, f: F) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(vm))) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            vm.store_panic(payload);
+            None
+        }
+    }
+}
+
+pub(crate) fn abort_on_panic<R, F: FnOnce() -> R>(f: F) -> R {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| process::abort())
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+// Wrapped functions are called through a reference made from `&()`, so they can't capture
+// anything. Evaluating `CHECK` fails the build for closures that do.
+struct ZeroSized<F>(PhantomData<F>);
+
+impl<F> ZeroSized<F> {
+    const CHECK: () = assert!(
+        mem::size_of::<F>() == 0,
+        "Wrapped closures must be zero-sized"
+    );
+}
+
 #[doc(hidden)]
 #[inline]
 fn _assert_size<F>() {
-    let size = mem::size_of::<F>();
-    assert!(size == 0, "Wrapped closures must be zero-sized");
+    let () = ZeroSized::<F>::CHECK;
 }
 
 #[doc(hidden)]
 #[inline]
 pub fn _wrap_reallocate_fn<F: Fn(Pointer, usize) -> Pointer>(_: F) -> ::ReallocateFn {
     unsafe extern "C" fn f<F: Fn(Pointer, usize) -> Pointer>(
-        _memory: *mut c_void,
+        memory: *mut c_void,
         new_size: size_t,
-        data: *mut c_void,
+        _: *mut c_void,
     ) -> *mut c_void {
-        mem::transmute::<&(), &F>(&())(data, new_size)
+        abort_on_panic(|| mem::transmute::<&(), &F>(&())(memory, new_size))
     }
     _assert_size::<F>();
     Some(f::<F>)
@@ -96,19 +214,130 @@ pub fn _wrap_reallocate_fn<F: Fn(Pointer, usize) -> Pointer>(_: F) -> ::Realloca
 
 #[doc(hidden)]
 #[inline]
-pub fn _wrap_foreign_method_fn<F: Fn(&mut VM)>(_: F) -> ::ForeignMethodFn {
+pub fn _wrap_foreign_method_fn<F: Fn(&mut VM)>(f: F) -> ::ForeignMethodFn {
+    _assert_size::<F>();
+    foreign_method_fn(f)
+}
+
+// Wrap `F` without checking its size, which must be zero.
+fn foreign_method_fn<F: Fn(&mut VM)>(_: F) -> ::ForeignMethodFn {
     unsafe extern "C" fn f<F: Fn(&mut VM)>(vm: *mut ffi::WrenVM) {
-        mem::transmute::<&(), &F>(&())(&mut VM::from_ptr(vm));
+        let mut vm = VM::from_ptr(vm);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            mem::transmute::<&(), &F>(&())(&mut vm);
+        }));
+        if let Err(payload) = result {
+            let message = format!("Foreign method panicked: {}", panic_message(&*payload));
+            vm.store_panic(payload);
+            vm.set_slot_string(0, &message);
+            vm.abort_fiber(0);
+        }
     }
-    _assert_size::<F>();
     Some(f::<F>)
 }
 
+#[doc(hidden)]
+#[inline]
+pub fn _wrap_foreign_fn<Args, F: ForeignFn<Args>>(f: F) -> ::ForeignMethodFn {
+    _assert_size::<F>();
+    foreign_fn(f)
+}
+
+// Wrap `F` without checking its size, which must be zero.
+pub(crate) fn foreign_fn<Args, F: ForeignFn<Args>>(f: F) -> ::ForeignMethodFn {
+    foreign_method_fn(move |vm: &mut VM| call_foreign_fn(vm, &f))
+}
+
+pub(crate) fn call_foreign_fn<Args, F: ForeignFn<Args>>(vm: &mut VM, f: &F) {
+    if let Err(message) = f.invoke(vm) {
+        vm.set_slot_string(0, &message);
+        vm.abort_fiber(0);
+    }
+}
+
+// Support functions for the code generated by `wren-derive`.
+
+#[doc(hidden)]
+#[inline]
+pub fn _wrap_method<F: Fn(&mut VM) -> Result<(), String>>(f: F) -> ::ForeignMethodFn {
+    _wrap_foreign_method_fn(move |vm: &mut VM| {
+        if let Err(message) = f(vm) {
+            vm.set_slot_string(0, &message);
+            vm.abort_fiber(0);
+        }
+    })
+}
+
+#[doc(hidden)]
+pub fn _allocate<T: WrenForeign>(vm: &mut VM, value: T) {
+    vm.new_foreign(0, 0, value);
+}
+
+#[doc(hidden)]
+pub fn _foreign<T: WrenForeign>(vm: &mut VM, slot: i32) -> Result<*mut T, String> {
+    match vm.get_foreign::<T>(slot) {
+        Some(value) => Ok(value as *mut T),
+        None => Err(format!(
+            "Expected {} in slot {}, found {:?}",
+            T::NAME,
+            slot,
+            vm.get_slot_type(slot)
+        )),
+    }
+}
+
+// `objects` lists the foreign objects a method borrows as `(pointer, slot, mutable)`. Borrowing
+// the same object twice is only sound if neither borrow is mutable.
+#[doc(hidden)]
+pub fn _check_aliasing(signature: &str, objects: &[(*const (), i32, bool)]) -> Result<(), String> {
+    for (i, &(first, first_slot, first_mut)) in objects.iter().enumerate() {
+        for &(second, second_slot, second_mut) in &objects[i + 1..] {
+            if first == second && (first_mut || second_mut) {
+                return Err(format!(
+                    "Slots {} and {} of {} are the same object, which is borrowed mutably",
+                    first_slot, second_slot, signature
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Lists and maps replace their slot before creating their elements. Slot 0 may hold the only
+// reference to the receiver that `value` borrows from, so the value is built in a free slot and
+// moved to slot 0 when it's complete.
+#[doc(hidden)]
+pub fn _set_result<T: ToWren>(vm: &mut VM, value: T) {
+    let slot = vm.get_slot_count();
+    vm.set(slot, value);
+    let handle = vm.get_slot_handle(slot);
+    vm.set_slot_handle(0, &handle);
+}
+
+#[doc(hidden)]
+pub fn _new_instance<T: WrenClass>(vm: &mut VM, slot: i32, value: T) -> Result<(), String> {
+    let module = match vm.context().bindings.foreign_class_module::<T>() {
+        Some(module) => module.to_string(),
+        None => return Err(format!("Foreign class {} is not registered", T::NAME)),
+    };
+    vm.get_variable(&module, T::NAME, slot)
+        .map_err(|e| e.to_string())?;
+    vm.new_foreign(slot, slot, value);
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn _signature<Args, F: ForeignFn<Args>>(name: &str, _: &F) -> String {
+    let params = vec!["_"; F::ARITY];
+    format!("{}({})", name, params.join(","))
+}
+
 #[doc(hidden)]
 #[inline]
 pub fn _wrap_finalizer_fn<F: Fn(Pointer)>(_: F) -> ::FinalizerFn {
     unsafe extern "C" fn f<F: Fn(Pointer)>(data: *mut c_void) {
-        mem::transmute::<&(), &F>(&())(data)
+        abort_on_panic(|| mem::transmute::<&(), &F>(&())(foreign::data(data)));
+        foreign::finalize(data)
     }
     _assert_size::<F>();
     Some(f::<F>)
@@ -126,11 +355,13 @@ pub fn _wrap_bind_foreign_method_fn<F: Fn(&mut VM, &str, &str, bool, &str) -> ::
         is_static: bool,
         signature: *const c_char,
     ) -> ::ForeignMethodFn {
-        let mut vm = VM::from_ptr(vm);
-        let module = CStr::from_ptr(module).to_str().unwrap();
-        let class_name = CStr::from_ptr(class_name).to_str().unwrap();
-        let signature = CStr::from_ptr(signature).to_str().unwrap();
-        mem::transmute::<&(), &F>(&())(&mut vm, module, class_name, is_static, signature)
+        catch(&mut VM::from_ptr(vm), |vm| {
+            let module = CStr::from_ptr(module).to_string_lossy();
+            let class_name = CStr::from_ptr(class_name).to_string_lossy();
+            let signature = CStr::from_ptr(signature).to_string_lossy();
+            mem::transmute::<&(), &F>(&())(vm, &module, &class_name, is_static, &signature)
+        })
+        .unwrap_or(None)
     }
     _assert_size::<F>();
     Some(f::<F>)
@@ -146,10 +377,114 @@ pub fn _wrap_bind_foreign_class_fn<F: Fn(&mut VM, &str, &str) -> ::ForeignClassM
         module: *const c_char,
         class_name: *const c_char,
     ) -> ffi::WrenForeignClassMethods {
-        let mut vm = VM::from_ptr(vm);
-        let module = CStr::from_ptr(module).to_str().unwrap();
-        let class_name = CStr::from_ptr(class_name).to_str().unwrap();
-        mem::transmute::<&(), &F>(&())(&mut vm, module, class_name).get()
+        let methods = catch(&mut VM::from_ptr(vm), |vm| {
+            let module = CStr::from_ptr(module).to_string_lossy();
+            let class_name = CStr::from_ptr(class_name).to_string_lossy();
+            mem::transmute::<&(), &F>(&())(vm, &module, &class_name)
+        });
+        methods.unwrap_or_else(panicked_class).get()
+    }
+    _assert_size::<F>();
+    Some(f::<F>)
+}
+
+// Used when binding a foreign class panics, as Wren would call a null allocator.
+fn panicked_class() -> ::ForeignClassMethods {
+    let mut methods = ::ForeignClassMethods::new();
+    methods.set_allocate_fn(_wrap_foreign_method_fn(|vm: &mut VM| {
+        vm.set_slot_string(0, "Binding the foreign class panicked.");
+        vm.abort_fiber(0);
+    }));
+    methods
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn _wrap_resolve_module_fn<F: Fn(&mut VM, &str, &str) -> Option<String>>(
+    _: F,
+) -> ::ResolveModuleFn {
+    unsafe extern "C" fn f<F: Fn(&mut VM, &str, &str) -> Option<String>>(
+        vm: *mut ffi::WrenVM,
+        importer: *const c_char,
+        name: *const c_char,
+    ) -> *const c_char {
+        let mut wrapper = VM::from_ptr(vm);
+        let name_str = CStr::from_ptr(name).to_string_lossy();
+        let resolved = catch(&mut wrapper, |vm| {
+            let importer = CStr::from_ptr(importer).to_string_lossy();
+            mem::transmute::<&(), &F>(&())(vm, &importer, &name_str)
+        });
+        let resolved = match resolved {
+            Some(Some(resolved)) => resolved,
+            _ => return ptr::null(),
+        };
+        if resolved == name_str {
+            // Wren keeps its own string if it gets the same pointer back.
+            return name;
+        }
+        if resolved.contains('\0') {
+            return ptr::null();
+        }
+        // Wren frees the resolved name with the reallocate function.
+        let len = resolved.len();
+        let buffer = wrapper.allocate_for_wren(len + 1) as *mut c_char;
+        if buffer.is_null() {
+            return ptr::null();
+        }
+        ptr::copy_nonoverlapping(resolved.as_ptr() as *const c_char, buffer, len);
+        *buffer.add(len) = 0;
+        buffer
+    }
+    _assert_size::<F>();
+    Some(f::<F>)
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn _wrap_load_module_fn<F: Fn(&mut VM, &str) -> Option<String>>(_: F) -> ::LoadModuleFn {
+    // Wren only reads the source while compiling the module, then hands it back here.
+    unsafe extern "C" fn complete(
+        _: *mut ffi::WrenVM,
+        _: *const c_char,
+        result: ffi::WrenLoadModuleResult,
+    ) {
+        drop(CString::from_raw(result.source as *mut c_char));
+    }
+
+    unsafe extern "C" fn f<F: Fn(&mut VM, &str) -> Option<String>>(
+        vm: *mut ffi::WrenVM,
+        name: *const c_char,
+    ) -> ffi::WrenLoadModuleResult {
+        let mut wrapper = VM::from_ptr(vm);
+        let name = CStr::from_ptr(name).to_string_lossy();
+        let source = catch(&mut wrapper, |vm| {
+            match vm.context().bindings.defined_module_source(&name) {
+                Some(source) => Some(source),
+                None => mem::transmute::<&(), &F>(&())(vm, &name),
+            }
+        });
+        match source.unwrap_or(None).map(CString::new) {
+            Some(Ok(source)) => ffi::WrenLoadModuleResult {
+                source: source.into_raw(),
+                onComplete: Some(complete),
+                userData: ptr::null_mut(),
+            },
+            Some(Err(e)) => {
+                // Wren can only report the module as missing, so the VM returns the real error.
+                let source = String::from_utf8(e.into_vec()).unwrap();
+                wrapper.store_nul_module(&name, source);
+                ffi::WrenLoadModuleResult {
+                    source: ptr::null(),
+                    onComplete: None,
+                    userData: ptr::null_mut(),
+                }
+            }
+            None => ffi::WrenLoadModuleResult {
+                source: ptr::null(),
+                onComplete: None,
+                userData: ptr::null_mut(),
+            },
+        }
     }
     _assert_size::<F>();
     Some(f::<F>)
@@ -159,10 +494,9 @@ pub fn _wrap_bind_foreign_class_fn<F: Fn(&mut VM, &str, &str) -> ::ForeignClassM
 #[inline]
 pub fn _wrap_write_fn<F: Fn(&mut VM, &str)>(_: F) -> ::WriteFn {
     unsafe extern "C" fn f<F: Fn(&mut VM, &str)>(vm: *mut ffi::WrenVM, text: *const c_char) {
-        mem::transmute::<&(), &F>(&())(
-            &mut VM::from_ptr(vm),
-            CStr::from_ptr(text).to_str().unwrap(),
-        );
+        catch(&mut VM::from_ptr(vm), |vm| {
+            mem::transmute::<&(), &F>(&())(vm, &CStr::from_ptr(text).to_string_lossy())
+        });
     }
     _assert_size::<F>();
     Some(f::<F>)
@@ -178,14 +512,51 @@ pub fn _wrap_error_fn<F: Fn(&mut VM, ErrorType, &str, i32, &str)>(_: F) -> ::Err
         line: c_int,
         message: *const c_char,
     ) {
-        let mut vm = VM::from_ptr(vm);
-        let module = if module == ptr::null() {
-            ""
-        } else {
-            CStr::from_ptr(module).to_str().unwrap()
-        };
-        let message = CStr::from_ptr(message).to_str().unwrap();
-        mem::transmute::<&(), &F>(&())(&mut vm, _type, module, line, message);
+        catch(&mut VM::from_ptr(vm), |vm| {
+            let module = if module == ptr::null() {
+                "".into()
+            } else {
+                CStr::from_ptr(module).to_string_lossy()
+            };
+            let message = CStr::from_ptr(message).to_string_lossy();
+            mem::transmute::<&(), &F>(&())(vm, _type, &module, line, &message)
+        });
+    }
+    _assert_size::<F>();
+    Some(f::<F>)
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn _wrap_write_bytes_fn<F: Fn(&mut VM, &[u8])>(_: F) -> ::WriteFn {
+    unsafe extern "C" fn f<F: Fn(&mut VM, &[u8])>(vm: *mut ffi::WrenVM, text: *const c_char) {
+        catch(&mut VM::from_ptr(vm), |vm| {
+            mem::transmute::<&(), &F>(&())(vm, CStr::from_ptr(text).to_bytes())
+        });
+    }
+    _assert_size::<F>();
+    Some(f::<F>)
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn _wrap_error_bytes_fn<F: Fn(&mut VM, ErrorType, &[u8], i32, &[u8])>(_: F) -> ::ErrorFn {
+    unsafe extern "C" fn f<F: Fn(&mut VM, ErrorType, &[u8], i32, &[u8])>(
+        vm: *mut ffi::WrenVM,
+        _type: ffi::WrenErrorType,
+        module: *const c_char,
+        line: c_int,
+        message: *const c_char,
+    ) {
+        catch(&mut VM::from_ptr(vm), |vm| {
+            let module = if module.is_null() {
+                &[]
+            } else {
+                CStr::from_ptr(module).to_bytes()
+            };
+            let message = CStr::from_ptr(message).to_bytes();
+            mem::transmute::<&(), &F>(&())(vm, _type, module, line, message)
+        });
     }
     _assert_size::<F>();
     Some(f::<F>)
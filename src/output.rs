@@ -0,0 +1,67 @@
+use log::Level;
+use std::io::Write;
+use std::mem;
+
+/// Where the output of `System.print` and `System.write` goes.
+pub enum Output {
+    /// Print to stdout, like the CLI interpreter. This is the default.
+    Stdout,
+    /// Write to any writer, ignoring errors.
+    Writer(Box<dyn Write>),
+    /// Collect the output in memory, to be retrieved with `VM::take_output`.
+    Buffer,
+    /// Log each line of output to a `log` target.
+    Log { target: String, level: Level },
+}
+
+// The output of a VM, with the collected text or the incomplete line to be logged.
+pub(crate) struct OutputSink {
+    output: Output,
+    buffer: Vec<u8>,
+}
+
+impl OutputSink {
+    pub(crate) fn new(output: Output) -> OutputSink {
+        OutputSink {
+            output,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub(crate) fn write(&mut self, text: &[u8]) {
+        match self.output {
+            Output::Stdout => print!("{}", String::from_utf8_lossy(text)),
+            Output::Writer(ref mut writer) => {
+                let _ = writer.write_all(text);
+            }
+            Output::Buffer => self.buffer.extend_from_slice(text),
+            Output::Log { ref target, level } => {
+                // `System.print` writes the text and the newline separately.
+                self.buffer.extend_from_slice(text);
+                while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                    let line = String::from_utf8_lossy(&line[..end]);
+                    log!(target: target, level, "{}", line);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        match self.output {
+            Output::Buffer => mem::take(&mut self.buffer),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Drop for OutputSink {
+    fn drop(&mut self) {
+        if let Output::Log { ref target, level } = self.output {
+            if !self.buffer.is_empty() {
+                let line = String::from_utf8_lossy(&self.buffer);
+                log!(target: target, level, "{}", line);
+            }
+        }
+    }
+}
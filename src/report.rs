@@ -0,0 +1,102 @@
+use log::Level;
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use ErrorType;
+
+/// A message passed by Wren to the error function.
+///
+/// Runtime errors are followed by one `ErrorType::StackTrace` report per frame, in which the
+/// message is the name of the function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub error_type: ErrorType,
+    /// The module of the error, or an empty string for runtime errors.
+    pub module: String,
+    pub line: i32,
+    pub message: String,
+}
+
+/// Formats the report like the Wren CLI prints it.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.error_type {
+            ErrorType::Compile => {
+                write!(f, "[{} line {}] {}", self.module, self.line, self.message)
+            }
+            ErrorType::Runtime => write!(f, "{}", self.message),
+            ErrorType::StackTrace => {
+                write!(
+                    f,
+                    "[{} line {}] in {}",
+                    self.module, self.line, self.message
+                )
+            }
+        }
+    }
+}
+
+/// Receives the errors reported by a VM, see `Configuration::set_reporter`.
+pub trait Reporter {
+    fn report(&mut self, report: &Report);
+}
+
+/// Print errors to stderr in the same format as the Wren CLI. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrReporter;
+
+impl Reporter for StderrReporter {
+    fn report(&mut self, report: &Report) {
+        eprintln!("{}", report);
+    }
+}
+
+/// Log errors to a `log` target, formatted like `StderrReporter`.
+///
+/// Errors and stack frames are logged at `Level::Error`. Use a bridge like `tracing-log` to
+/// receive them with `tracing`.
+#[derive(Clone, Debug)]
+pub struct LogReporter {
+    target: String,
+}
+
+impl LogReporter {
+    pub fn new(target: &str) -> LogReporter {
+        LogReporter {
+            target: target.to_string(),
+        }
+    }
+}
+
+impl Reporter for LogReporter {
+    fn report(&mut self, report: &Report) {
+        log!(target: &self.target, Level::Error, "{}", report);
+    }
+}
+
+/// Collect errors in memory.
+///
+/// Clones share the collected reports, so keep a clone to retrieve them after passing the
+/// reporter to `Configuration::set_reporter`.
+#[derive(Clone, Debug, Default)]
+pub struct CollectingReporter {
+    reports: Rc<RefCell<Vec<Report>>>,
+}
+
+impl CollectingReporter {
+    pub fn new() -> CollectingReporter {
+        CollectingReporter::default()
+    }
+
+    /// Take the reports collected so far.
+    pub fn take(&self) -> Vec<Report> {
+        mem::take(&mut *self.reports.borrow_mut())
+    }
+}
+
+impl Reporter for CollectingReporter {
+    fn report(&mut self, report: &Report) {
+        self.reports.borrow_mut().push(report.clone());
+    }
+}
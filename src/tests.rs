@@ -1,4 +1,14 @@
-use {Configuration, VM};
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+use std::ptr;
+use std::rc::Rc;
+use {
+    foreign, macros, CallHandleStats, CollectingReporter, Configuration, Error, ErrorType,
+    ForeignClassMethods, ForeignMethodFn, Handle, InterpretResult, LogReporter, Output, Pointer,
+    Report, Type, Value, WrenError, WrenFunction, MAX_CLOSURES, VM,
+};
 
 #[test]
 fn list() {
@@ -18,6 +28,43 @@ fn list() {
     assert_eq!(vm.get_slot_double(1).unwrap(), 3.0);
 }
 
+#[test]
+fn list_set_and_remove() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_new_list_from(0, 1..4, |vm, slot, i| vm.set_slot_double(slot, i as f64));
+    vm.set_slot_string(1, "two");
+    vm.set_list_element(0, 1, 1);
+    vm.set_slot_bool(2, true);
+
+    vm.remove_from_list(0, -1, 3).unwrap();
+    assert_eq!(vm.get_slot_double(3).unwrap(), 3.0);
+    assert_eq!(vm.get_slot_string(1).unwrap(), "two");
+    assert!(vm.get_slot_bool(2).unwrap());
+
+    let types = vm.get_list_elements(0, |vm, slot| vm.get_slot_type(slot));
+    assert_eq!(types, vec![Type::Num, Type::String]);
+    let first = vm.get_list_elements(0, |vm, slot| vm.get_slot_double(slot));
+    assert_eq!(first[0], Some(1.0));
+
+    match vm.remove_from_list(0, 2, 3) {
+        Err(Error::OutOfRange { value, .. }) => assert_eq!(value, 2.0),
+        other => panic!("Expected an out of range error, got {:?}", other),
+    }
+    assert_eq!(vm.get_list_count(0), 2);
+    assert_eq!(vm.get_slot_string(1).unwrap(), "two");
+    vm.remove_from_list(0, 0, 3).unwrap();
+    assert_eq!(vm.get_slot_double(3).unwrap(), 1.0);
+    assert_eq!(vm.call_handle_stats().hits, 1);
+}
+
+#[test]
+#[should_panic]
+fn list_get_out_of_bounds() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_new_list_from(0, vec![()], |vm, slot, _| vm.set_slot_null(slot));
+    vm.get_list_element(0, 1, 1);
+}
+
 #[test]
 #[should_panic]
 fn list_out_of_bounds() {
@@ -43,3 +90,1294 @@ fn list_no_element() {
     vm.set_slot_new_list(0);
     vm.insert_in_list(0, -1, 1);
 }
+
+#[test]
+fn map() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_new_map(0);
+    vm.set_slot_string(1, "one");
+    vm.set_slot_double(2, 1.0);
+    vm.set_map_value(0, 1, 2);
+    vm.set_slot_double(1, 2.0);
+    vm.set_slot_bool(2, true);
+    vm.set_map_value(0, 1, 2);
+    assert_eq!(vm.get_map_count(0), 2);
+
+    vm.set_slot_string(1, "one");
+    assert!(vm.get_map_contains_key(0, 1));
+    vm.get_map_value(0, 1, 3);
+    assert_eq!(vm.get_slot_double(3).unwrap(), 1.0);
+
+    vm.remove_map_value(0, 1, 3);
+    assert_eq!(vm.get_slot_double(3).unwrap(), 1.0);
+    assert!(!vm.get_map_contains_key(0, 1));
+    assert_eq!(vm.get_map_count(0), 1);
+
+    vm.get_map_value(0, 1, 3);
+    assert_eq!(vm.get_slot_type(3), Type::Null);
+}
+
+#[test]
+#[should_panic]
+fn not_map() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_new_list(0);
+    vm.set_slot_double(1, 1.0);
+    vm.get_map_contains_key(0, 1);
+}
+
+#[test]
+#[should_panic]
+fn map_invalid_key() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_new_map(0);
+    vm.set_slot_new_list(1);
+    vm.set_slot_null(2);
+    vm.set_map_value(0, 1, 2);
+}
+
+#[test]
+#[should_panic]
+fn map_no_value() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_new_map(0);
+    vm.set_slot_double(1, 1.0);
+    vm.set_map_value(0, 1, 2);
+}
+
+#[test]
+fn variables() {
+    let mut vm = VM::new(Configuration::new());
+    vm.interpret("var answer = 42").unwrap();
+    assert!(vm.has_module("main"));
+    assert!(!vm.has_module("missing"));
+    assert!(vm.has_variable("main", "answer"));
+    assert!(!vm.has_variable("main", "question"));
+    assert!(!vm.has_variable("missing", "answer"));
+
+    vm.get_variable("main", "answer", 0).unwrap();
+    assert_eq!(vm.get_slot_double(0).unwrap(), 42.0);
+    match vm.get_variable("main", "question", 0) {
+        Err(Error::UnknownVariable { ref name, .. }) => assert_eq!(name, "question"),
+        _ => panic!("Expected an unknown variable error"),
+    }
+    match vm.get_variable("missing", "answer", 0) {
+        Err(Error::UnknownModule(ref module)) => assert_eq!(module, "missing"),
+        _ => panic!("Expected an unknown module error"),
+    }
+}
+
+#[test]
+fn unresolved_module() {
+    fn resolve(_: &mut VM, importer: &str, name: &str) -> Option<String> {
+        assert_eq!(importer, "main");
+        assert_eq!(name, "missing");
+        None
+    }
+    let mut cfg = Configuration::new();
+    cfg.set_resolve_module_fn(wren_resolve_module_fn!(resolve));
+    let mut vm = VM::new(cfg);
+    assert_eq!(
+        vm.interpret("import \"missing\"").unwrap(),
+        InterpretResult::RuntimeError
+    );
+}
+
+fn load_util(_: &mut VM, name: &str) -> Option<String> {
+    if name == "util" {
+        Some("var answer = 42".to_string())
+    } else {
+        None
+    }
+}
+
+#[test]
+fn load_module() {
+    let mut cfg = Configuration::new();
+    cfg.set_load_module_fn(wren_load_module_fn!(load_util));
+    let mut vm = VM::new(cfg);
+    assert_eq!(
+        vm.interpret("import \"util\" for answer").unwrap(),
+        InterpretResult::Success
+    );
+    vm.get_variable("main", "answer", 0).unwrap();
+    assert_eq!(vm.get_slot_double(0).unwrap(), 42.0);
+    assert_eq!(
+        vm.interpret("import \"missing\"").unwrap(),
+        InterpretResult::RuntimeError
+    );
+}
+
+#[test]
+fn load_module_with_nul_byte() {
+    fn load(_: &mut VM, name: &str) -> Option<String> {
+        if name == "broken" {
+            Some("var a = 1\nvar b = \0".to_string())
+        } else {
+            None
+        }
+    }
+    let reporter = CollectingReporter::new();
+    let mut cfg = Configuration::new();
+    cfg.set_load_module_fn(wren_load_module_fn!(load));
+    cfg.set_reporter(reporter.clone());
+    let mut vm = VM::new(cfg);
+
+    // `interpret` keeps Wren's result and reports the NUL byte as a compile error.
+    assert_eq!(
+        vm.interpret("import \"broken\"").unwrap(),
+        InterpretResult::RuntimeError
+    );
+    let reports = reporter.take();
+    let report = reports.last().unwrap();
+    assert_eq!(report.error_type, ErrorType::Compile);
+    assert_eq!((report.module.as_str(), report.line), ("broken", 2));
+    assert_eq!(report.message, "Unexpected NUL byte.");
+
+    let expected = WrenError::NulByte {
+        module: "broken".to_string(),
+        position: 18,
+    };
+    assert_eq!(vm.run("import \"broken\""), Err(expected.clone()));
+    match vm.run_in_module("other", "import \"broken\"") {
+        Err(Error::Wren(error)) => assert_eq!(error, expected),
+        other => panic!("Expected a NUL byte error, got {:?}", other),
+    }
+    assert!(reporter.take().is_empty());
+    assert_eq!(
+        vm.interpret("import \"missing\"").unwrap(),
+        InterpretResult::RuntimeError
+    );
+}
+
+#[test]
+fn resolve_module() {
+    fn resolve(_: &mut VM, _: &str, name: &str) -> Option<String> {
+        Some(name.trim_start_matches("./").to_string())
+    }
+    let mut cfg = Configuration::new();
+    cfg.set_resolve_module_fn(wren_resolve_module_fn!(resolve));
+    cfg.set_load_module_fn(wren_load_module_fn!(load_util));
+    let mut vm = VM::new(cfg);
+    assert_eq!(
+        vm.interpret("import \"./util\" for answer").unwrap(),
+        InterpretResult::Success
+    );
+    assert!(vm.has_module("util"));
+}
+
+#[test]
+fn resolve_module_with_reallocate_fn() {
+    thread_local! {
+        static BLOCKS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+        static UNKNOWN_FREES: Cell<usize> = const { Cell::new(0) };
+    }
+    // Only frees blocks it allocated itself.
+    fn reallocate(memory: Pointer, new_size: usize) -> Pointer {
+        BLOCKS.with(|blocks| {
+            let mut blocks = blocks.borrow_mut();
+            if !memory.is_null() && !blocks.remove(&(memory as usize)) {
+                UNKNOWN_FREES.with(|frees| frees.set(frees.get() + 1));
+                return ptr::null_mut();
+            }
+            let result = macros::_default_realloc(memory, new_size);
+            if !result.is_null() {
+                blocks.insert(result as usize);
+            }
+            result
+        })
+    }
+    fn resolve(_: &mut VM, _: &str, name: &str) -> Option<String> {
+        Some(name.trim_start_matches("./").to_string())
+    }
+
+    let mut cfg = Configuration::new();
+    cfg.set_reallocate_fn(wren_reallocate_fn!(reallocate));
+    cfg.set_resolve_module_fn(wren_resolve_module_fn!(resolve));
+    cfg.set_load_module_fn(wren_load_module_fn!(load_util));
+    let mut vm = VM::new(cfg);
+    assert_eq!(
+        vm.interpret("import \"./util\" for answer").unwrap(),
+        InterpretResult::Success
+    );
+    drop(vm);
+    assert_eq!(UNKNOWN_FREES.with(Cell::get), 0);
+}
+
+#[test]
+fn non_utf8_strings() {
+    thread_local! {
+        static WRITTEN: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+    fn write(_: &mut VM, text: &[u8]) {
+        WRITTEN.with(|written| written.borrow_mut().extend_from_slice(text));
+    }
+
+    let mut cfg = Configuration::new();
+    cfg.set_write_fn(wren_write_bytes_fn!(write));
+    let mut vm = VM::new(cfg);
+    let source = "var s = String.fromByte(255) + \"!\"\nSystem.write(s)";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    WRITTEN.with(|written| assert_eq!(*written.borrow(), b"\xff!"));
+
+    vm.get_variable("main", "s", 0).unwrap();
+    assert_eq!(vm.get_slot_bytes(0).unwrap(), b"\xff!");
+    assert_eq!(vm.get_slot_string(0).unwrap(), "\u{fffd}!");
+
+    let mut vm = VM::new(Configuration::new());
+    let source = "System.print(String.fromByte(255))\nFiber.abort(String.fromByte(254))";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::RuntimeError);
+    assert!(vm.take_panic().is_none());
+}
+
+#[test]
+fn nul_bytes() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set_slot_string(0, "a\0b");
+    assert_eq!(vm.get_slot_bytes(0).unwrap(), b"a\0b");
+
+    match vm.interpret("var a = 1\0") {
+        Err(Error::NulByte(position)) => assert_eq!(position, 9),
+        other => panic!("Expected a NUL byte error, got {:?}", other),
+    }
+    assert!(vm.interpret_in_module("ma\0in", "").is_err());
+    assert!(vm.make_call_handle("call(\0)").is_err());
+    assert!(!vm.has_module("ma\0in"));
+    assert!(!vm.has_variable("main", "a\0"));
+    match vm.get_variable("ma\0in", "a", 0) {
+        Err(Error::NulByte(position)) => assert_eq!(position, 2),
+        other => panic!("Expected a NUL byte error, got {:?}", other),
+    }
+    match vm.get_variable("main", "a\0", 0) {
+        Err(Error::NulByte(position)) => assert_eq!(position, 1),
+        other => panic!("Expected a NUL byte error, got {:?}", other),
+    }
+}
+
+#[test]
+fn run_errors() {
+    fn error(_: &mut VM, _: ErrorType, _: &str, _: i32, _: &str) {
+        panic!("errors should be returned");
+    }
+
+    let mut cfg = Configuration::new();
+    cfg.set_error_fn(wren_error_fn!(error));
+    let mut vm = VM::new(cfg);
+    assert_eq!(vm.run("var answer = 42"), Ok(()));
+    match vm.run("var x = 1\nvar y = ") {
+        Err(WrenError::Compile(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].module, "main");
+            assert_eq!(errors[0].line, 2);
+        }
+        other => panic!("Expected a compile error, got {:?}", other),
+    }
+
+    let source = "class Foo {\n  static bar() {\n    Fiber.abort(\"oops\")\n  }\n}\nFoo.bar()";
+    match vm.run(source) {
+        Err(WrenError::Runtime {
+            message,
+            stack_trace,
+        }) => {
+            assert_eq!(message, "oops");
+            let lines: Vec<i32> = stack_trace.iter().map(|frame| frame.line).collect();
+            assert_eq!(lines, vec![3, 6]);
+            assert_eq!(stack_trace[1].function, "(script)");
+        }
+        other => panic!("Expected a runtime error, got {:?}", other),
+    }
+
+    vm.get_variable("main", "Foo", 0).unwrap();
+    let bar = vm.make_call_handle("bar()").unwrap();
+    let error = vm.try_call(&bar).unwrap_err();
+    assert!(error.to_string().starts_with("oops\n[main line 3] in "));
+
+    let expected = WrenError::NulByte {
+        module: "main".to_string(),
+        position: 9,
+    };
+    assert_eq!(vm.run("var z = 1\0"), Err(expected.clone()));
+    match vm.run_in_module("main", "var z = 1\0") {
+        Err(Error::Wren(error)) => assert_eq!(error, expected),
+        other => panic!("Expected a NUL byte error, got {:?}", other),
+    }
+    match vm.run_in_module("ma\0in", "var z = 1") {
+        Err(Error::NulByte(position)) => assert_eq!(position, 2),
+        other => panic!("Expected a NUL byte error, got {:?}", other),
+    }
+    match vm.run_in_module("other", "var z = ") {
+        Err(Error::Wren(WrenError::Compile(errors))) => assert_eq!(errors[0].module, "other"),
+        other => panic!("Expected a compile error, got {:?}", other),
+    }
+    assert!(vm.take_panic().is_none());
+    assert_eq!(
+        vm.interpret("Fiber.abort(1)").unwrap(),
+        InterpretResult::RuntimeError
+    );
+    assert!(vm.take_panic().is_some());
+}
+
+#[test]
+fn output() {
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut cfg = Configuration::new();
+    cfg.set_output(Output::Buffer);
+    let mut vm = VM::new(cfg);
+    vm.run("System.print(\"Hello\")\nSystem.write(42)").unwrap();
+    assert_eq!(vm.take_output(), "Hello\n42");
+    assert_eq!(vm.take_output(), "");
+
+    let written = Rc::new(RefCell::new(Vec::new()));
+    let mut cfg = Configuration::new();
+    cfg.set_output(Output::Writer(Box::new(Shared(written.clone()))));
+    let mut vm = VM::new(cfg);
+    vm.run("System.print([1, 2])").unwrap();
+    assert_eq!(*written.borrow(), b"[1, 2]\n");
+    assert_eq!(vm.take_output(), "");
+}
+
+#[test]
+fn log_output() {
+    take_logged();
+    let mut cfg = Configuration::new();
+    cfg.set_output(Output::Log {
+        target: "wren::output".to_string(),
+        level: Level::Info,
+    });
+    let mut vm = VM::new(cfg);
+    let source = "System.write(\"a\")\nSystem.print(\"b\\nc\")\nSystem.write(\"partial\")";
+    vm.run(source).unwrap();
+    let logged = take_logged();
+    assert!(logged
+        .iter()
+        .all(|(target, level, _)| target == "wren::output" && *level == Level::Info));
+    let lines: Vec<&str> = logged.iter().map(|(_, _, line)| &line[..]).collect();
+    assert_eq!(lines, vec!["ab", "c"]);
+
+    // The last line is logged when the VM is freed, even without a newline.
+    drop(vm);
+    let lines: Vec<String> = take_logged().into_iter().map(|(_, _, line)| line).collect();
+    assert_eq!(lines, vec!["partial"]);
+}
+
+// Collects the records logged on the current thread, so tests using it can run in parallel.
+struct TestLogger;
+
+thread_local! {
+    static LOGGED: RefCell<Vec<(String, Level, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let entry = (
+            record.target().to_string(),
+            record.level(),
+            record.args().to_string(),
+        );
+        LOGGED.with(|logged| logged.borrow_mut().push(entry));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: TestLogger = TestLogger;
+
+// Take the records logged on this thread, installing the logger first.
+fn take_logged() -> Vec<(String, Level, String)> {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(LevelFilter::Trace);
+    LOGGED.with(|logged| logged.borrow_mut().drain(..).collect())
+}
+
+#[test]
+fn report_format() {
+    let report = |error_type, module: &str, line, message: &str| Report {
+        error_type,
+        module: module.to_string(),
+        line,
+        message: message.to_string(),
+    };
+    assert_eq!(
+        report(ErrorType::Compile, "main", 2, "Error at 'x': Oops.").to_string(),
+        "[main line 2] Error at 'x': Oops."
+    );
+    assert_eq!(
+        report(ErrorType::Runtime, "", 0, "oops").to_string(),
+        "oops"
+    );
+    assert_eq!(
+        report(ErrorType::StackTrace, "util", 7, "Foo.bar()").to_string(),
+        "[util line 7] in Foo.bar()"
+    );
+}
+
+#[test]
+fn log_reporter() {
+    take_logged();
+    let mut cfg = Configuration::new();
+    cfg.set_reporter(LogReporter::new("wren::test"));
+    let mut vm = VM::new(cfg);
+    let source = "class Foo {\n  static bar() { Fiber.abort(\"oops\") }\n}\nFoo.bar()";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::RuntimeError);
+    assert_eq!(
+        vm.interpret("var x = ").unwrap(),
+        InterpretResult::CompileError
+    );
+
+    let logged = take_logged();
+    assert!(logged
+        .iter()
+        .all(|(target, level, _)| target == "wren::test" && *level == Level::Error));
+    let messages: Vec<&str> = logged.iter().map(|(_, _, message)| &message[..]).collect();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[0], "oops");
+    assert!(messages[1].starts_with("[main line 2] in "));
+    assert!(messages[2].starts_with("[main line 4] in "));
+    assert!(messages[3].starts_with("[main line 1] Error"));
+}
+
+#[test]
+fn collecting_reporter() {
+    let reporter = CollectingReporter::new();
+    let mut cfg = Configuration::new();
+    cfg.set_reporter(reporter.clone());
+    let mut vm = VM::new(cfg);
+    let source = "class Foo {\n  static bar() { Fiber.abort(\"oops\") }\n}\nFoo.bar()";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::RuntimeError);
+
+    let reports = reporter.take();
+    assert_eq!(reports.len(), 3);
+    assert_eq!(reports[0].error_type, ErrorType::Runtime);
+    assert_eq!(reports[0].message, "oops");
+    assert_eq!(reports[1].error_type, ErrorType::StackTrace);
+    assert_eq!((reports[1].module.as_str(), reports[1].line), ("main", 2));
+    assert_eq!(reports[2].line, 4);
+
+    // Errors returned by `run` aren't reported.
+    assert!(vm.run("var x = ").is_err());
+    assert!(reporter.take().is_empty());
+    assert_eq!(
+        vm.interpret("var x = ").unwrap(),
+        InterpretResult::CompileError
+    );
+    assert_eq!(reporter.take()[0].error_type, ErrorType::Compile);
+}
+
+#[test]
+fn call_method() {
+    let mut vm = VM::new(Configuration::new());
+    let source =
+        "class Math {\n  static add(a, b) { a + b }\n  static fail() { Fiber.abort(\"no\") }\n}\n\
+                  var list = [1, 2]";
+    vm.run(source).unwrap();
+
+    let sum: f64 = vm
+        .call_method(("main", "Math"), "add(_,_)", (1, 2))
+        .unwrap();
+    assert_eq!(sum, 3.0);
+    let joined: String = vm
+        .call_method(("main", "Math"), "add(_,_)", ("a", "b"))
+        .unwrap();
+    assert_eq!(joined, "ab");
+
+    vm.get_variable("main", "list", 0).unwrap();
+    let list = vm.get_slot_handle(0);
+    let count: usize = vm.call_method(&list, "count", ()).unwrap();
+    assert_eq!(count, 2);
+    let first: i32 = vm.call_method(&list, "[_]", (0,)).unwrap();
+    assert_eq!(first, 1);
+
+    match vm.call_method::<_, _, ()>(("main", "Math"), "fail()", ()) {
+        Err(Error::Wren(WrenError::Runtime { message, .. })) => assert_eq!(message, "no"),
+        other => panic!("Expected a runtime error, got {:?}", other),
+    }
+    match vm.call_method::<_, _, ()>(("main", "Missing"), "fail()", ()) {
+        Err(Error::UnknownVariable { name, .. }) => assert_eq!(name, "Missing"),
+        other => panic!("Expected an unknown variable error, got {:?}", other),
+    }
+    assert!(vm.call_method::<_, _, String>(&list, "count", ()).is_err());
+}
+
+#[test]
+#[should_panic]
+fn call_method_wrong_arity() {
+    let mut vm = VM::new(Configuration::new());
+    vm.run("class Math {\n  static add(a, b) { a + b }\n}")
+        .unwrap();
+    let _: Result<f64, Error> = vm.call_method(("main", "Math"), "add(_,_)", (1,));
+}
+
+#[test]
+fn call_handle_cache() {
+    let mut vm = VM::new(Configuration::new());
+    vm.run("class Game {\n  static update(dt) {}\n}").unwrap();
+    vm.get_variable("main", "Game", 0).unwrap();
+    let game = vm.get_slot_handle(0);
+
+    for _ in 0..3 {
+        let update = vm.call_handle("update(_)").unwrap();
+        vm.set_slot_handle(0, &game);
+        vm.set_slot_double(1, 0.1);
+        vm.try_call(&update).unwrap();
+    }
+    assert_eq!(
+        vm.call_handle_stats(),
+        CallHandleStats { hits: 2, misses: 1 }
+    );
+
+    let () = vm.call_method(&game, "update(_)", (0.1,)).unwrap();
+    assert_eq!(vm.call_handle_stats().hits, 3);
+    assert!(vm.call_handle("bad\0").is_err());
+    assert_eq!(vm.call_handle_stats().misses, 1);
+}
+
+#[test]
+fn wren_function() {
+    let mut vm = VM::new(Configuration::new());
+    vm.run("var add = Fn.new {|a, b| a + b }\nvar list = [1, 2]")
+        .unwrap();
+
+    vm.get_variable("main", "add", 0).unwrap();
+    let handle = vm.get_slot_handle(0);
+    let add = WrenFunction::from_fn(&mut vm, handle).unwrap();
+    assert_eq!(add.signature(), "call(_,_)");
+    for i in 0..3 {
+        let sum: f64 = add.call(&mut vm, (i, 1)).unwrap();
+        assert_eq!(sum, i as f64 + 1.0);
+    }
+
+    vm.get_variable("main", "list", 0).unwrap();
+    let list = vm.get_slot_handle(0);
+    let add = WrenFunction::new(&mut vm, list.clone(), "add(_)").unwrap();
+    let _: Value = add.call(&mut vm, (3,)).unwrap();
+    let count: usize = vm.call_method(&list, "count", ()).unwrap();
+    assert_eq!(count, 3);
+
+    match WrenFunction::from_fn(&mut vm, list) {
+        Err(Error::Wren(WrenError::Runtime { .. })) => {}
+        other => panic!("Expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn handle_outlives_vm() {
+    let mut vm = VM::new(Configuration::new());
+    vm.run("var list = [1, 2]").unwrap();
+    vm.get_variable("main", "list", 0).unwrap();
+    let list = vm.get_slot_handle(0);
+    let update = vm.make_call_handle("update(_)").unwrap();
+    drop(vm);
+    drop(list);
+    drop(update);
+}
+
+#[test]
+#[should_panic(expected = "Handle belongs to a different VM")]
+fn handle_from_other_vm() {
+    let mut vm = VM::new(Configuration::new());
+    let count = vm.make_call_handle("count").unwrap();
+    let mut other = VM::new(Configuration::new());
+    other.set_slot_handle(0, &count);
+}
+
+#[test]
+fn release_handle() {
+    let mut vm = VM::new(Configuration::new());
+    vm.run("var list = [1, 2]").unwrap();
+    vm.get_variable("main", "list", 0).unwrap();
+    let list = vm.get_slot_handle(0);
+    let copy = list.clone();
+    list.release(&mut vm);
+    assert!(copy.is_released());
+
+    // Cached call handles are shared, so releasing one only drops that reference.
+    vm.get_variable("main", "list", 0).unwrap();
+    let list = vm.get_slot_handle(0);
+    let count = WrenFunction::new(&mut vm, list.clone(), "count").unwrap();
+    let handle = vm.call_handle("count").unwrap();
+    vm.release_handle(handle);
+    assert!(!vm.call_handle("count").unwrap().is_released());
+    let length: usize = count.call(&mut vm, ()).unwrap();
+    assert_eq!(length, 2);
+    let length: usize = vm.call_method(&list, "count", ()).unwrap();
+    assert_eq!(length, 2);
+    assert_eq!(vm.call_handle_stats().misses, 1);
+}
+
+#[test]
+#[should_panic(expected = "Handle has been released")]
+fn use_released_handle() {
+    let mut vm = VM::new(Configuration::new());
+    let count = vm.make_call_handle("count").unwrap();
+    let copy = count.clone();
+    vm.release_handle(count);
+    vm.call(&copy);
+}
+
+#[test]
+fn track_handles() {
+    let mut cfg = Configuration::new();
+    cfg.set_track_handles(true);
+    let mut vm = VM::new(cfg);
+    let line = line!() + 1;
+    let count = vm.make_call_handle("count").unwrap();
+    let live = vm.live_handles();
+    assert_eq!(live.len(), 1);
+    assert_eq!((live[0].file(), live[0].line()), (file!(), line));
+
+    count.release(&mut vm);
+    assert!(vm.live_handles().is_empty());
+
+    // Handles created by conversions and calls are recorded where they were called.
+    vm.run("var values = [Fn.new {}, [Fn.new {}]]").unwrap();
+    vm.get_variable("main", "values", 0).unwrap();
+    let line = line!() + 1;
+    let values: Vec<Value> = vm.get(0).unwrap();
+    let first: Handle = vm.call_method(("main", "values"), "[_]", (0,)).unwrap();
+    // Two from the list, the call handle and the result.
+    let live = vm.live_handles();
+    assert_eq!(live.len(), 4);
+    for location in live {
+        assert_eq!(location.file(), file!());
+        assert!(location.line() == line || location.line() == line + 1);
+    }
+
+    // The cached call handle isn't a leak.
+    let leaked = vm.free();
+    assert_eq!(leaked.len(), 3);
+    assert!(first.is_released());
+    drop(values);
+
+    let untracked = VM::new(Configuration::new()).live_handles();
+    assert!(untracked.is_empty());
+}
+
+#[test]
+fn leaked_handles() {
+    let mut cfg = Configuration::new();
+    cfg.set_track_handles(true);
+    let mut vm = VM::new(cfg);
+    vm.set_slot_new_list(0);
+    let line = line!() + 1;
+    let list = vm.get_slot_handle(0);
+    let leaked = vm.free();
+    assert_eq!(leaked.len(), 1);
+    assert_eq!((leaked[0].file(), leaked[0].line()), (file!(), line));
+    assert!(list.is_released());
+
+    // Dropping the VM logs where the handles were created instead.
+    take_logged();
+    let mut cfg = Configuration::new();
+    cfg.set_track_handles(true);
+    let mut vm = VM::new(cfg);
+    vm.set_slot_new_list(0);
+    let line = line!() + 1;
+    let _list = vm.get_slot_handle(0);
+    drop(vm);
+    let logged = take_logged();
+    assert_eq!(logged.len(), 1);
+    let (_, level, ref message) = logged[0];
+    assert_eq!(level, Level::Warn);
+    let expected = format!("Handle created at {}:{}:", file!(), line);
+    assert!(message.starts_with(&expected));
+}
+
+#[test]
+fn values() {
+    let mut vm = VM::new(Configuration::new());
+    let value = Value::List(vec![
+        Value::Null,
+        Value::Bool(true),
+        Value::Bytes(vec![0xff, 0xfe]),
+        Value::List(vec![Value::Num(1.0), Value::String("two".to_string())]),
+        Value::Map(vec![(Value::String("key".to_string()), Value::Num(3.0))]),
+    ]);
+    vm.set_slot(0, &value);
+    assert_eq!(vm.get_list_count(0), 5);
+    vm.get_list_element(0, 4, 1);
+    assert_eq!(vm.get_map_count(1), 1);
+
+    let elements = match vm.get_slot(0) {
+        Value::List(elements) => elements,
+        other => panic!("Expected a list, got {:?}", other),
+    };
+    assert_eq!(elements.len(), 5);
+    match (&elements[0], &elements[1], &elements[2]) {
+        (Value::Null, Value::Bool(true), Value::Bytes(bytes)) => assert_eq!(bytes, &[0xff, 0xfe]),
+        _ => panic!("Unexpected list elements {:?}", elements),
+    }
+    match &elements[3] {
+        Value::List(inner) => assert_eq!(inner.len(), 2),
+        other => panic!("Expected a list, got {:?}", other),
+    }
+    match &elements[4] {
+        Value::Unknown(_) => {}
+        other => panic!("Expected a map handle, got {:?}", other),
+    }
+}
+
+#[test]
+fn conversions() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set(0, 42u32);
+    assert_eq!(vm.get::<i64>(0).unwrap(), 42);
+    assert_eq!(vm.get::<f32>(0).unwrap(), 42.0);
+    assert!(vm.get::<bool>(0).is_err());
+
+    vm.set(0, "text");
+    assert_eq!(vm.get::<String>(0).unwrap(), "text");
+    assert_eq!(vm.get::<Vec<u8>>(0).unwrap(), b"text");
+    vm.set(0, vec![0xffu8]);
+    assert_eq!(vm.get::<Vec<u8>>(0).unwrap(), [0xff]);
+
+    vm.set(0, vec![Some(1i32), None]);
+    assert_eq!(vm.get::<Vec<Option<i32>>>(0).unwrap(), [Some(1), None]);
+    vm.set(0, (true, "two", 3.5));
+    assert_eq!(
+        vm.get::<(bool, String, f64)>(0).unwrap(),
+        (true, "two".to_string(), 3.5)
+    );
+    match vm.get::<(bool, String)>(0) {
+        Err(Error::WrongLength { expected, found }) => assert_eq!((expected, found), (2, 3)),
+        other => panic!("Expected a length error, got {:?}", other),
+    }
+
+    let mut map = BTreeMap::new();
+    map.insert("a", 1);
+    map.insert("b", 2);
+    vm.set(0, &map);
+    assert_eq!(vm.get_map_count(0), 2);
+    match vm.get::<Value>(0).unwrap() {
+        Value::Unknown(_) => {}
+        other => panic!("Expected a map handle, got {:?}", other),
+    }
+
+    // u8 isn't a number, so lists of small numbers can't be read as bytes.
+    vm.set(0, vec![1u16, 2]);
+    assert_eq!(vm.get::<Vec<u16>>(0).unwrap(), [1, 2]);
+    match vm.get::<Vec<u8>>(0) {
+        Err(Error::WrongType { expected, found }) => {
+            assert_eq!((expected, found), (Type::String, Type::List))
+        }
+        other => panic!("Expected a type error, got {:?}", other),
+    }
+
+    vm.set(0, ());
+    assert_eq!(vm.get::<Option<String>>(0).unwrap(), None);
+}
+
+#[test]
+fn integer_conversions() {
+    let mut vm = VM::new(Configuration::new());
+    vm.set(0, 1.5);
+    assert!(vm.get::<i32>(0).is_err());
+    vm.set(0, -1);
+    assert!(vm.get::<u32>(0).is_err());
+    vm.set(0, 65536);
+    assert!(vm.get::<u16>(0).is_err());
+    vm.set(0, 18446744073709551616.0);
+    assert!(vm.get::<u64>(0).is_err());
+    vm.set(0, -9223372036854775808.0);
+    assert_eq!(vm.get::<i64>(0).unwrap(), i64::MIN);
+}
+
+fn add(a: f64, b: f64) -> f64 {
+    a + b
+}
+
+fn bind_math(
+    _: &mut VM,
+    module: &str,
+    class_name: &str,
+    is_static: bool,
+    signature: &str,
+) -> ForeignMethodFn {
+    if module == "main" && class_name == "Math" && is_static && signature == wren_signature!(add) {
+        wren_foreign_fn!(add)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn foreign_fn() {
+    assert_eq!(wren_signature!(add), "add(_,_)");
+    assert_eq!(wren_signature!("plus", add), "plus(_,_)");
+
+    let mut cfg = Configuration::new();
+    cfg.set_bind_foreign_method_fn(wren_bind_foreign_method_fn!(bind_math));
+    let mut vm = VM::new(cfg);
+    let source = "class Math {\n  foreign static add(a, b)\n}\nvar sum = Math.add(1, 2)";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    vm.get_variable("main", "sum", 0).unwrap();
+    assert_eq!(vm.get::<f64>(0).unwrap(), 3.0);
+
+    assert_eq!(
+        vm.interpret("Math.add(1, \"2\")").unwrap(),
+        InterpretResult::RuntimeError
+    );
+}
+
+#[test]
+fn foreign_bindings() {
+    let mut cfg = Configuration::new();
+    cfg.bindings_mut().add_fn("main", "Math", "plus", true, add);
+    let mut vm = VM::new(cfg);
+    let source = "class Math {\n  foreign static plus(a, b)\n}\nvar sum = Math.plus(1, 2)";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    vm.get_variable("main", "sum", 0).unwrap();
+    assert_eq!(vm.get::<f64>(0).unwrap(), 3.0);
+
+    let source = "class Math2 {\n  foreign static minus(a, b)\n}";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::RuntimeError);
+}
+
+#[test]
+fn foreign_bindings_with_state() {
+    let offset = 10.0;
+    let mut cfg = Configuration::new();
+    cfg.bindings_mut()
+        .add_fn("main", "Math", "shift", true, move |x: f64| x + offset);
+    let mut vm = VM::new(cfg);
+    let source = "class Math {\n  foreign static shift(x)\n}\nvar shifted = Math.shift(1)";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    vm.get_variable("main", "shifted", 0).unwrap();
+    assert_eq!(vm.get::<f64>(0).unwrap(), 11.0);
+    assert_eq!(
+        vm.interpret("Math.shift(\"1\")").unwrap(),
+        InterpretResult::RuntimeError
+    );
+}
+
+#[test]
+fn generated_source() {
+    let mut cfg = Configuration::new();
+    {
+        let bindings = cfg.bindings_mut();
+        bindings.add_fn("math", "Math", "plus", true, add);
+        bindings.add_closure("math", "Point", "x", false, |vm: &mut VM| vm.set(0, 1.0));
+        bindings.add_closure("math", "Point", "[_,_]=(_)", false, |_: &mut VM| {});
+        bindings.add_class("math", "Point", ForeignClassMethods::new());
+        bindings.add_constructor("math", "Point", "new()");
+        bindings.define_module("math");
+    }
+    assert_eq!(
+        cfg.bindings_mut().class_source("math", "Point").unwrap(),
+        "foreign class Point {\n  construct new() {}\n  foreign [a, b]=(c)\n  foreign x\n}\n"
+    );
+    assert_eq!(
+        cfg.bindings_mut().module_source("math").unwrap(),
+        "class Math {\n  foreign static plus(a, b)\n}\n\nforeign class Point {\n  \
+         construct new() {}\n  foreign [a, b]=(c)\n  foreign x\n}\n"
+    );
+    assert!(cfg.bindings_mut().module_source("main").is_none());
+
+    let mut vm = VM::new(cfg);
+    let source = "import \"math\" for Math\nvar sum = Math.plus(1, 2)";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    vm.get_variable("main", "sum", 0).unwrap();
+    assert_eq!(vm.get::<f64>(0).unwrap(), 3.0);
+}
+
+#[test]
+fn unbound_foreign_class() {
+    let mut vm = VM::new(Configuration::new());
+    let source = "foreign class Point {\n  construct new() {}\n}\nPoint.new()";
+    match vm.run(source) {
+        Err(WrenError::Runtime { message, .. }) => assert_eq!(
+            message,
+            "Foreign class 'Point' in module 'main' has not been bound."
+        ),
+        other => panic!("Expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn many_unbound_foreign_classes() {
+    let mut vm = VM::new(Configuration::new());
+    let source: String = (0..MAX_CLOSURES + 1)
+        .map(|i| format!("foreign class Point{} {{}}\n", i))
+        .collect();
+    assert_eq!(vm.interpret(&source).unwrap(), InterpretResult::Success);
+}
+
+#[test]
+fn random_module() {
+    let mut vm = VM::new(Configuration::new());
+    let source = "import \"random\" for Random\nvar x = Random.new(1).float()";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    vm.get_variable("main", "x", 0).unwrap();
+    let x = vm.get::<f64>(0).unwrap();
+    assert!((0.0..1.0).contains(&x));
+}
+
+#[test]
+fn foreign_objects_from_c() {
+    let mut vm = VM::new(Configuration::new());
+    let source = "import \"random\" for Random\nvar random = Random.new(1)\nvar x";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+
+    // Objects of classes implemented in C have no header to read a type from.
+    vm.get_variable("main", "random", 0).unwrap();
+    assert_eq!(vm.get_slot_type(0), Type::Foreign);
+    assert!(vm.get_foreign::<f64>(0).is_none());
+    assert!(vm.get_foreign::<[u32; 17]>(0).is_none());
+    assert_eq!(
+        vm.interpret("x = random.float()").unwrap(),
+        InterpretResult::Success
+    );
+}
+
+#[test]
+fn foreign_objects_without_finalizer() {
+    let mut cfg = Configuration::new();
+    {
+        let bindings = cfg.bindings_mut();
+        let mut methods = ForeignClassMethods::new();
+        methods.set_allocate_fn(bindings.closure_fn(|vm: &mut VM| {
+            vm.new_foreign(0, 0, 5u32);
+        }));
+        methods.set_finalize_fn(None);
+        bindings.add_class("main", "Plain", methods);
+    }
+    let mut vm = VM::new(cfg);
+    let source = "foreign class Plain {\n  construct new() {}\n}\nvar a = Plain.new()";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+
+    vm.get_variable("main", "a", 0).unwrap();
+    assert_eq!(vm.get_foreign::<u32>(0), Some(&mut 5));
+    let raw = (vm.get_slot_foreign(0).unwrap() as usize - foreign::HEADER_SIZE) as Pointer;
+    assert_eq!(vm.interpret("a = null").unwrap(), InterpretResult::Success);
+    vm.collect_garbage();
+
+    // The object is forgotten when its memory is freed, even without a finalizer.
+    unsafe { assert!(foreign::type_id(raw).is_none()) };
+    assert_eq!(foreign::data(raw), raw);
+}
+
+#[test]
+fn foreign_values() {
+    struct Tracked(Rc<Cell<i32>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    let drops = Rc::new(Cell::new(0));
+    let mut cfg = Configuration::new();
+    {
+        let bindings = cfg.bindings_mut();
+        let captured = drops.clone();
+        let mut methods = ForeignClassMethods::new();
+        methods.set_allocate_fn(bindings.closure_fn(move |vm: &mut VM| {
+            vm.new_foreign(0, 0, Tracked(captured.clone()));
+        }));
+        bindings.add_class("main", "Tracked", methods);
+    }
+    let mut vm = VM::new(cfg);
+    let source =
+        "foreign class Tracked {\n  construct new() {}\n}\nvar a = Tracked.new()\nTracked.new()";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+
+    vm.get_variable("main", "a", 0).unwrap();
+    assert!(vm.get_foreign::<Tracked>(0).is_some());
+    assert!(vm.get_foreign::<f64>(0).is_none());
+    vm.set_slot_double(1, 1.0);
+    assert!(vm.get_foreign::<f64>(1).is_none());
+
+    drop(vm);
+    assert_eq!(drops.get(), 2);
+}
+
+#[test]
+fn callback_panics() {
+    fn explode() -> f64 {
+        panic!("boom")
+    }
+    fn write(_: &mut VM, _: &str) {
+        panic!("write")
+    }
+
+    let mut cfg = Configuration::new();
+    cfg.bindings_mut()
+        .add_fn("main", "Bomb", "explode", true, explode);
+    cfg.set_write_fn(wren_write_fn!(write));
+    let mut vm = VM::new(cfg);
+    let source = "class Bomb {\n  foreign static explode()\n}\n\
+                  var error = Fiber.new { Bomb.explode() }.try()";
+    assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+    vm.get_variable("main", "error", 0).unwrap();
+    assert_eq!(
+        vm.get_slot_string(0).unwrap(),
+        "Foreign method panicked: boom"
+    );
+    let payload = vm.take_panic().unwrap();
+    assert_eq!(*payload.downcast::<&str>().unwrap(), "boom");
+    assert!(vm.take_panic().is_none());
+
+    assert_eq!(
+        vm.interpret("System.print(1)").unwrap(),
+        InterpretResult::Success
+    );
+    assert!(vm.take_panic().is_some());
+}
+
+#[test]
+fn vm_data() {
+    struct Counter {
+        count: i32,
+        drops: Rc<Cell<i32>>,
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    let drops = Rc::new(Cell::new(0));
+    let mut cfg = Configuration::new();
+    cfg.bindings_mut()
+        .add_closure("main", "Counter", "next()", true, |vm: &mut VM| {
+            let counter = vm.data::<Counter>().unwrap();
+            counter.count += 1;
+            let count = counter.count;
+            vm.set(0, count);
+        });
+    let counter = Counter {
+        count: 0,
+        drops: drops.clone(),
+    };
+    let mut vm = VM::new_with_data(cfg, counter);
+    let source = "class Counter {\n  foreign static next()\n}\nCounter.next()\nCounter.next()";
+    vm.run(source).unwrap();
+    assert_eq!(vm.data::<Counter>().unwrap().count, 2);
+    assert!(vm.data::<i32>().is_none());
+    assert!(VM::new(Configuration::new()).data::<Counter>().is_none());
+
+    drop(vm);
+    assert_eq!(drops.get(), 1);
+}
+
+#[test]
+fn foreign_closures() {
+    fn counter_vm(start: i32) -> (VM, Rc<Cell<i32>>) {
+        let count = Rc::new(Cell::new(start));
+        let captured = count.clone();
+        let mut cfg = Configuration::new();
+        cfg.bindings_mut()
+            .add_closure("main", "Counter", "next()", true, move |vm: &mut VM| {
+                captured.set(captured.get() + 1);
+                vm.set(0, captured.get());
+            });
+        (VM::new(cfg), count)
+    }
+
+    let source = "class Counter {\n  foreign static next()\n}\nvar last = Counter.next()";
+    let (mut first, first_count) = counter_vm(0);
+    let (mut second, second_count) = counter_vm(10);
+    assert_eq!(first.interpret(source).unwrap(), InterpretResult::Success);
+    assert_eq!(
+        first.interpret("last = Counter.next()").unwrap(),
+        InterpretResult::Success
+    );
+    assert_eq!(second.interpret(source).unwrap(), InterpretResult::Success);
+
+    assert_eq!(first_count.get(), 2);
+    assert_eq!(second_count.get(), 11);
+    second.get_variable("main", "last", 0).unwrap();
+    assert_eq!(second.get::<i32>(0).unwrap(), 11);
+}
+
+#[cfg(feature = "derive")]
+mod derive {
+    use {wren_class, Configuration, InterpretResult, WrenForeign, VM};
+
+    #[derive(Default, WrenForeign)]
+    pub struct Counter {
+        pub count: i32,
+        #[wren(name = "stepSize")]
+        pub step: i32,
+        #[wren(skip)]
+        pub hidden: bool,
+    }
+
+    #[wren_class]
+    impl Counter {
+        pub fn increment(&mut self) -> i32 {
+            self.count += self.step;
+            self.count
+        }
+
+        pub fn merge(&self, other: &Counter) -> Counter {
+            Counter {
+                count: self.count + other.count,
+                step: self.step,
+                hidden: false,
+            }
+        }
+
+        pub fn absorb(&mut self, other: &Counter) {
+            self.count += other.count;
+        }
+
+        #[wren(getter, name = "isHidden")]
+        fn is_hidden(&self) -> bool {
+            self.hidden
+        }
+
+        #[wren(name = "startingAt")]
+        pub fn starting_at(count: i32) -> Counter {
+            Counter {
+                count,
+                step: 1,
+                hidden: true,
+            }
+        }
+    }
+
+    #[derive(Default, WrenForeign)]
+    pub struct Tag {
+        pub id: i32,
+    }
+
+    #[wren_class]
+    impl Tag {}
+
+    #[test]
+    fn derive_class() {
+        let mut cfg = Configuration::new();
+        cfg.bindings_mut().add_foreign_class::<Counter>("main");
+        cfg.bindings_mut().add_foreign_class::<Tag>("main");
+        let mut vm = VM::new(cfg);
+        let source = r#"
+foreign class Counter {
+  construct new() {}
+  foreign count
+  foreign count=(value)
+  foreign stepSize
+  foreign stepSize=(value)
+  foreign increment()
+  foreign merge(other)
+  foreign absorb(other)
+  foreign isHidden
+  foreign static startingAt(count)
+}
+foreign class Tag {
+  construct new() {}
+}
+var a = Counter.new()
+a.stepSize = 2
+a.increment()
+a.increment()
+var b = Counter.startingAt(10)
+var sum = a.merge(b).count
+var hidden = [a.isHidden, b.isHidden]
+"#;
+        assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+        vm.get_variable("main", "sum", 0).unwrap();
+        assert_eq!(vm.get::<i32>(0).unwrap(), 14);
+        vm.get_variable("main", "hidden", 0).unwrap();
+        assert_eq!(vm.get::<Vec<bool>>(0).unwrap(), vec![false, true]);
+
+        let result = vm.interpret("a.count = \"four\"").unwrap();
+        assert_eq!(result, InterpretResult::RuntimeError);
+        assert_eq!(
+            vm.interpret("Counter.new().merge(1)").unwrap(),
+            InterpretResult::RuntimeError
+        );
+        assert_eq!(
+            vm.interpret("Counter.new().merge(Tag.new())").unwrap(),
+            InterpretResult::RuntimeError
+        );
+        let source = "import \"random\" for Random\nCounter.new().merge(Random.new(1))";
+        assert_eq!(vm.interpret(source).unwrap(), InterpretResult::RuntimeError);
+
+        // Shared borrows of the same object are fine, a mutable one is not.
+        assert_eq!(
+            vm.interpret("sum = a.merge(a).count").unwrap(),
+            InterpretResult::Success
+        );
+        vm.get_variable("main", "sum", 0).unwrap();
+        assert_eq!(vm.get::<i32>(0).unwrap(), 8);
+        assert_eq!(
+            vm.interpret("a.absorb(a)").unwrap(),
+            InterpretResult::RuntimeError
+        );
+        assert_eq!(
+            vm.interpret("a.absorb(b)\nsum = a.count").unwrap(),
+            InterpretResult::Success
+        );
+        vm.get_variable("main", "sum", 0).unwrap();
+        assert_eq!(vm.get::<i32>(0).unwrap(), 14);
+    }
+
+    #[derive(WrenForeign)]
+    pub struct Bag {
+        pub items: Vec<String>,
+    }
+
+    #[wren_class]
+    impl Bag {
+        #[wren(construct)]
+        pub fn new(count: i32) -> Bag {
+            Bag {
+                items: (0..count).map(|i| i.to_string()).collect(),
+            }
+        }
+
+        pub fn first(&self) -> &String {
+            &self.items[0]
+        }
+    }
+
+    #[test]
+    fn getter_on_temporary_receiver() {
+        let mut cfg = Configuration::new();
+        // Collect on every allocation so a receiver that isn't rooted is freed mid-getter.
+        cfg.set_initial_heap_size(0);
+        cfg.set_min_heap_size(0);
+        cfg.set_heap_growth_percent(0);
+        cfg.bindings_mut().add_foreign_class::<Bag>("main");
+        let mut vm = VM::new(cfg);
+        let source = r#"
+foreign class Bag {
+  construct new(count) {}
+  foreign items
+  foreign first()
+}
+var lists = []
+var firsts = []
+for (i in 1..10) {
+  lists.add(Bag.new(i).items)
+  firsts.add(Bag.new(i).first())
+}
+"#;
+        assert_eq!(vm.interpret(source).unwrap(), InterpretResult::Success);
+        vm.get_variable("main", "lists", 0).unwrap();
+        let lists = vm.get::<Vec<Vec<String>>>(0).unwrap();
+        for (i, list) in lists.iter().enumerate() {
+            let expected: Vec<String> = (0..i + 1).map(|i| i.to_string()).collect();
+            assert_eq!(*list, expected);
+        }
+        vm.get_variable("main", "firsts", 0).unwrap();
+        assert_eq!(vm.get::<Vec<String>>(0).unwrap(), vec!["0"; 10]);
+    }
+}
@@ -0,0 +1,23 @@
+use Handle;
+
+/// An owned copy of a value stored in a slot.
+///
+/// See `VM::get_slot` and `VM::set_slot`.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Num(f64),
+    String(String),
+    /// A string that isn't valid UTF-8.
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Map entries as key-value pairs.
+    ///
+    /// Keys must be valid Wren map keys (`Null`, `Bool`, `Num`, `String` or a class).
+    Map(Vec<(Value, Value)>),
+    /// A foreign object.
+    Foreign(Handle),
+    /// Any other object, such as a class, function, range or map retrieved from Wren.
+    Unknown(Handle),
+}
@@ -1,25 +1,39 @@
 use ffi;
-use libc::c_char;
+use foreign;
+use libc::{c_char, c_int, c_void, size_t};
+use macros;
+use output::OutputSink;
+use std::any::{type_name, Any, TypeId};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
+use std::fmt;
 use std::io;
 use std::mem;
+use std::panic::Location;
+use std::ptr;
 use std::rc::Rc;
 use std::slice;
-use {ErrorType, InterpretResult, Pointer, Type};
+use {
+    Error, ErrorType, ForeignBindings, FromWren, InterpretResult, Output, Pointer, Receiver,
+    Report, Reporter, StderrReporter, ToWren, Type, Value, WrenArgs, WrenError, MAX_CLOSURES,
+};
 
-fn default_write(_: &mut VM, text: &str) {
-    print!("{}", text);
+fn write_output(vm: &mut VM, text: &[u8]) {
+    vm.context().output.write(text);
 }
 
-fn default_error(_: &mut VM, _type: ErrorType, module: &str, line: i32, message: &str) {
-    match _type {
-        ErrorType::Compile => println!("[{} line {}] {}", module, line, message),
-        ErrorType::Runtime => println!("{}", message),
-        ErrorType::StackTrace => println!("[{} line {}] in {}", module, line, message),
-    }
+fn report_error_to(vm: &mut VM, error_type: ErrorType, module: &str, line: i32, message: &str) {
+    let report = Report {
+        error_type,
+        module: module.to_string(),
+        line,
+        message: message.to_string(),
+    };
+    vm.context().reporter.report(&report);
 }
 
-#[allow(dead_code)]
 fn default_load_module(_: &mut VM, name: &str) -> Option<String> {
     use std::fs::File;
     use std::io::Read;
@@ -49,8 +63,142 @@ fn default_load_module(_: &mut VM, name: &str) -> Option<String> {
     }
 }
 
+fn bind_foreign_method(
+    vm: &mut VM,
+    module: &str,
+    class_name: &str,
+    is_static: bool,
+    signature: &str,
+) -> ::ForeignMethodFn {
+    // Wren reports a missing method itself, as a runtime error where the class is defined.
+    vm.context()
+        .bindings
+        .method(module, class_name, signature, is_static)
+}
+
+fn bind_foreign_class(vm: &mut VM, module: &str, class_name: &str) -> ForeignClassMethods {
+    if let Some(methods) = vm.context().bindings.class(module, class_name) {
+        return methods;
+    }
+    // Wren binds the classes of its optional `random` module itself, but only if we return no
+    // methods for them.
+    if module == "random" {
+        return ForeignClassMethods(ffi::WrenForeignClassMethods {
+            allocate: None,
+            finalize: None,
+        });
+    }
+    // Wren would call a null allocator when constructing an instance of a missing class. A bind
+    // function can't fail, and outside of foreign methods there are no slots to abort the fiber
+    // with, so the error is raised by the allocator. Wren passes no class to it, so each class
+    // gets a closure naming it while the bindings have room, and a generic allocator after that.
+    let message = format!(
+        "Foreign class '{}' in module '{}' has not been bound.",
+        class_name, module
+    );
+    let bindings = &mut vm.context().bindings;
+    let mut methods = ForeignClassMethods::new();
+    if bindings.closure_count() < MAX_CLOSURES {
+        methods.set_allocate_fn(bindings.closure_fn(move |vm: &mut VM| {
+            vm.set_slot_string(0, &message);
+            vm.abort_fiber(0);
+        }));
+    } else {
+        methods.set_allocate_fn(wren_foreign_method_fn!(unbound_class_allocate));
+    }
+    methods
+}
+
+fn unbound_class_allocate(vm: &mut VM) {
+    vm.set_slot_string(0, "Foreign class has not been bound.");
+    vm.abort_fiber(0);
+}
+
+// Wren's reallocate function, which passes every call on to the configured function and lets
+// `foreign` know about them to keep track of foreign objects.
+unsafe extern "C" fn reallocate(
+    memory: *mut c_void,
+    new_size: size_t,
+    user_data: *mut c_void,
+) -> *mut c_void {
+    if !memory.is_null() {
+        foreign::freed(memory);
+    }
+    let result = match (*(user_data as *mut Context)).reallocate_fn {
+        Some(reallocate) => reallocate(memory, new_size, user_data),
+        None => macros::_default_realloc(memory, new_size),
+    };
+    if memory.is_null() {
+        foreign::allocated(result);
+    }
+    result
+}
+
+// Wren's error function, which collects the messages while running with `VM::run` and passes
+// them on to the configured function otherwise.
+unsafe extern "C" fn report_error(
+    vm: *mut ffi::WrenVM,
+    error_type: ErrorType,
+    module: *const c_char,
+    line: c_int,
+    message: *const c_char,
+) {
+    let mut wrapper = VM::from_ptr(vm);
+    let context = wrapper.context();
+    match context.reports {
+        Some(ref mut reports) => {
+            let module = if module.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(module).to_string_lossy().into_owned()
+            };
+            reports.push(Report {
+                error_type,
+                module,
+                line,
+                message: CStr::from_ptr(message).to_string_lossy().into_owned(),
+            });
+        }
+        None => {
+            if let Some(error_fn) = context.error_fn {
+                error_fn(vm, error_type, module, line, message);
+            }
+        }
+    }
+}
+
+// The number of parameters in a signature like `name(_,_)` or `[_]=(_)`.
+pub(crate) fn signature_arity(signature: &str) -> usize {
+    match signature.find(['(', '[']) {
+        Some(start) => signature[start..].matches('_').count(),
+        None => 0,
+    }
+}
+
+// Names and sources passed to Wren can't contain NUL bytes.
+fn c_string(s: &str) -> Result<CString, Error> {
+    CString::new(s).map_err(|e| Error::NulByte(e.nul_position()))
+}
+
+// The error `VM::run` returns for a module whose source contains a NUL byte.
+fn nul_byte_error(module: &str, source: &str) -> WrenError {
+    WrenError::NulByte {
+        module: module.to_string(),
+        position: source.find('\0').unwrap_or(0),
+    }
+}
+
 /// Wrapper around `WrenConfiguration`. Refer to `wren.h` for info on each field.
-pub struct Configuration(ffi::WrenConfiguration);
+///
+/// Foreign methods and classes can be registered with `bindings_mut`, which are looked up by the
+/// default bind functions.
+pub struct Configuration {
+    raw: ffi::WrenConfiguration,
+    bindings: ForeignBindings,
+    output: Output,
+    reporter: Box<dyn Reporter>,
+    track_handles: bool,
+}
 
 impl Configuration {
     /// Create a new Configuration using `wrenInitConfiguration`.
@@ -60,82 +208,241 @@ impl Configuration {
     /// See: https://stackoverflow.com/questions/61318595/writing-to-a-field-in-a-maybeuninit-structure
     ///
     pub fn new() -> Configuration {
-        let mut raw: ffi::WrenConfiguration =
-            unsafe { mem::MaybeUninit::<ffi::WrenConfiguration>::uninit().assume_init() };
-        unsafe { ffi::wrenInitConfiguration(&mut raw) }
-        let mut cfg = Configuration(raw);
-        cfg.set_write_fn(wren_write_fn!(default_write));
-        cfg.set_error_fn(wren_error_fn!(default_error));
+        let mut raw = mem::MaybeUninit::<ffi::WrenConfiguration>::uninit();
+        let raw = unsafe {
+            ffi::wrenInitConfiguration(raw.as_mut_ptr());
+            raw.assume_init()
+        };
+        let mut cfg = Configuration {
+            raw,
+            bindings: ForeignBindings::new(),
+            output: Output::Stdout,
+            reporter: Box::new(StderrReporter),
+            track_handles: false,
+        };
+        cfg.set_write_fn(wren_write_bytes_fn!(write_output));
+        cfg.set_error_fn(wren_error_fn!(report_error_to));
+        cfg.set_load_module_fn(wren_load_module_fn!(default_load_module));
+        cfg.set_bind_foreign_method_fn(wren_bind_foreign_method_fn!(bind_foreign_method));
+        cfg.set_bind_foreign_class_fn(wren_bind_foreign_class_fn!(bind_foreign_class));
         cfg
     }
 
+    /// The foreign methods and classes available to scripts.
+    ///
+    /// These are only used by the default bind functions, so replacing them with
+    /// `set_bind_foreign_method_fn` or `set_bind_foreign_class_fn` bypasses the bindings.
+    pub fn bindings_mut(&mut self) -> &mut ForeignBindings {
+        &mut self.bindings
+    }
+
     pub fn set_reallocate_fn(&mut self, f: ::ReallocateFn) {
-        self.0.reallocate_fn = f;
+        self.raw.reallocate_fn = f;
+    }
+
+    pub fn set_resolve_module_fn(&mut self, f: ::ResolveModuleFn) {
+        self.raw.resolve_module_fn = f;
     }
 
     pub fn set_load_module_fn(&mut self, f: ::LoadModuleFn) {
-        self.0.load_module_fn = f;
+        self.raw.load_module_fn = f;
     }
 
     pub fn set_bind_foreign_method_fn(&mut self, f: ::BindForeignMethodFn) {
-        self.0.bind_foreign_method_fn = f;
+        self.raw.bind_foreign_method_fn = f;
     }
 
     pub fn set_bind_foreign_class_fn(&mut self, f: ::BindForeignClassFn) {
-        self.0.bind_foreign_class_fn = f;
+        self.raw.bind_foreign_class_fn = f;
     }
 
     pub fn set_write_fn(&mut self, f: ::WriteFn) {
-        self.0.write_fn = f;
+        self.raw.write_fn = f;
+    }
+
+    /// Send the output of scripts to `output` instead of stdout.
+    ///
+    /// This replaces the write function set with `set_write_fn`.
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+        self.set_write_fn(wren_write_bytes_fn!(write_output));
     }
 
     pub fn set_error_fn(&mut self, f: ::ErrorFn) {
-        self.0.error_fn = f;
+        self.raw.error_fn = f;
+    }
+
+    /// Pass errors to `reporter` instead of printing them to stderr.
+    ///
+    /// This replaces the error function set with `set_error_fn`.
+    pub fn set_reporter<R: Reporter + 'static>(&mut self, reporter: R) {
+        self.reporter = Box::new(reporter);
+        self.set_error_fn(wren_error_fn!(report_error_to));
+    }
+
+    /// Record where each handle was created and log the ones still alive when the VM is freed.
+    ///
+    /// The creation sites of live handles are returned by `VM::live_handles`, and those of the
+    /// handles still alive when the VM is freed by `VM::free`.
+    pub fn set_track_handles(&mut self, track: bool) {
+        self.track_handles = track;
     }
 
     pub fn set_initial_heap_size(&mut self, size: usize) {
-        self.0.initial_heap_size = size;
+        self.raw.initial_heap_size = size;
     }
 
     pub fn set_min_heap_size(&mut self, size: usize) {
-        self.0.min_heap_size = size;
+        self.raw.min_heap_size = size;
     }
 
     pub fn set_heap_growth_percent(&mut self, percent: i32) {
-        self.0.heap_growth_percent = percent;
+        self.raw.heap_growth_percent = percent;
     }
 
+    /// The data is kept by the VM and returned by `VM::get_user_data`. Wren's own user data is
+    /// reserved for the VM, so a raw reallocate function doesn't receive this pointer.
+    ///
+    /// Prefer `VM::new_with_data`, which owns and drops the data.
     pub fn set_user_data(&mut self, data: Pointer) {
-        self.0.user_data = data;
+        self.raw.user_data = data;
     }
 }
 
 /// Reference-counted wrapper around `WrenHandle`.
 ///
 /// Automatically calls `wrenReleaseHandle` when there are no more references.
+/// Handles can only be used with the VM that created them. Handles that outlive their VM are
+/// released when it's freed and can't be used anymore.
 #[derive(Clone)]
 pub struct Handle(Rc<RawHandle>);
 
+impl Handle {
+    /// Release the handle now instead of when the last reference is dropped.
+    ///
+    /// See `VM::release_handle`.
+    pub fn release(self, vm: &mut VM) {
+        vm.release_handle(self)
+    }
+
+    /// Whether the handle has been released explicitly or by freeing its VM.
+    pub fn is_released(&self) -> bool {
+        self.0.raw.get().is_null() || self.0.registry.vm.get().is_null()
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Handle").field(&self.0.raw.get()).finish()
+    }
+}
+
 struct RawHandle {
-    raw: *mut ffi::WrenHandle,
-    vm: *mut ffi::WrenVM,
+    // Null once released.
+    raw: Cell<*mut ffi::WrenHandle>,
+    registry: Rc<HandleRegistry>,
+}
+
+impl RawHandle {
+    fn release(&self) {
+        let raw = self.raw.replace(ptr::null_mut());
+        if !raw.is_null() {
+            self.registry.release(raw)
+        }
+    }
 }
 
 impl Drop for RawHandle {
     fn drop(&mut self) {
-        unsafe { ffi::wrenReleaseHandle(self.vm, self.raw) }
+        self.release()
     }
 }
 
+// The live handles of a VM, shared with the handles themselves.
+struct HandleRegistry {
+    // Null once the VM has been freed.
+    vm: Cell<*mut ffi::WrenVM>,
+    // Live handles, with the place they were created if tracking is enabled.
+    handles: RefCell<HashMap<*mut ffi::WrenHandle, Option<&'static Location<'static>>>>,
+    track: bool,
+    // Where handles are being created, if that's further up than their caller. See `HandleOrigin`.
+    origin: Cell<Option<&'static Location<'static>>>,
+}
+
+impl HandleRegistry {
+    fn new(track: bool) -> HandleRegistry {
+        HandleRegistry {
+            vm: Cell::new(ptr::null_mut()),
+            handles: RefCell::new(HashMap::new()),
+            track,
+            origin: Cell::new(None),
+        }
+    }
+
+    #[track_caller]
+    fn insert(&self, raw: *mut ffi::WrenHandle) {
+        let location = match self.origin.get() {
+            _ if !self.track => None,
+            Some(origin) => Some(origin),
+            None => Some(Location::caller()),
+        };
+        self.handles.borrow_mut().insert(raw, location);
+    }
+
+    fn release(&self, raw: *mut ffi::WrenHandle) {
+        let vm = self.vm.get();
+        if !vm.is_null() && self.handles.borrow_mut().remove(&raw).is_some() {
+            unsafe { ffi::wrenReleaseHandle(vm, raw) }
+        }
+    }
+
+    // Release all handles before the VM is freed, returning where they were created if tracking
+    // is enabled.
+    unsafe fn release_all(&self) -> Vec<&'static Location<'static>> {
+        let vm = self.vm.replace(ptr::null_mut());
+        let mut leaked = Vec::new();
+        for (raw, location) in self.handles.borrow_mut().drain() {
+            leaked.extend(location);
+            ffi::wrenReleaseHandle(vm, raw);
+        }
+        leaked
+    }
+}
+
+// Records handles as created at the caller of a public function until it's dropped. Handles are
+// often created in closures or through function pointers, which can't pass on their caller.
+// Nested guards keep the outermost location.
+struct HandleOrigin(Option<Rc<HandleRegistry>>);
+
+impl Drop for HandleOrigin {
+    fn drop(&mut self) {
+        if let Some(ref registry) = self.0 {
+            registry.origin.set(None);
+        }
+    }
+}
+
+/// Cache statistics returned by `VM::call_handle_stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CallHandleStats {
+    /// Calls that reused a cached handle.
+    pub hits: usize,
+    /// Calls that created a new handle.
+    pub misses: usize,
+}
+
 /// Wrapper around `WrenForeignClassMethods`.
 #[derive(Copy, Clone)]
 pub struct ForeignClassMethods(ffi::WrenForeignClassMethods);
 
 impl ForeignClassMethods {
+    /// Create class methods without an allocator.
+    ///
+    /// The default finalizer drops the values of objects created with `VM::new_foreign`.
     pub fn new() -> ForeignClassMethods {
         ForeignClassMethods(ffi::WrenForeignClassMethods {
             allocate: None,
-            finalize: None,
+            finalize: Some(foreign::finalize),
         })
     }
 
@@ -143,6 +450,10 @@ impl ForeignClassMethods {
         self.0.allocate = f;
     }
 
+    /// Set the finalizer of the class.
+    ///
+    /// Values moved into objects with `VM::set_slot_new_foreign` are only dropped by finalizers
+    /// made with `wren_finalizer_fn!`, so use it rather than a raw function.
     pub fn set_finalize_fn(&mut self, f: ::FinalizerFn) {
         self.0.finalize = f;
     }
@@ -161,23 +472,75 @@ impl ForeignClassMethods {
 ///
 /// 2. `wrenEnsureSlots` is called automatically where needed.
 ///
-/// 3. Functions that operate on lists will validate their parameters.
+/// 3. Functions that operate on lists and maps will validate their parameters.
 pub struct VM {
     raw: *mut ffi::WrenVM,
     owned: bool,
 }
 
+// Rust-side state of a VM.
+//
+// Wren's user data points to this, so it's also reachable from the wrappers created in callbacks.
+pub(crate) struct Context {
+    pub(crate) bindings: ForeignBindings,
+    user_data: Pointer,
+    reallocate_fn: ffi::WrenReallocateFn,
+    panic: Option<Box<dyn Any + Send>>,
+    error_fn: ffi::WrenErrorFn,
+    reports: Option<Vec<Report>>,
+    // The name and source of the first module whose source couldn't be passed to Wren.
+    nul_module: Option<(String, String)>,
+    output: OutputSink,
+    reporter: Box<dyn Reporter>,
+    data: Option<Box<dyn Any>>,
+    call_handles: HashMap<String, Handle>,
+    call_handle_stats: CallHandleStats,
+    handles: Rc<HandleRegistry>,
+}
+
 impl VM {
     /// Create a new VM.
     pub fn new(cfg: Configuration) -> VM {
+        VM::with_context(cfg, None)
+    }
+
+    /// Create a new VM that owns `data`, which can be retrieved with `data`.
+    ///
+    /// The data is dropped after the VM is freed, so it's still available to finalizers.
+    pub fn new_with_data<T: 'static>(cfg: Configuration, data: T) -> VM {
+        VM::with_context(cfg, Some(Box::new(data)))
+    }
+
+    fn with_context(cfg: Configuration, data: Option<Box<dyn Any>>) -> VM {
         let mut cfg = cfg;
-        let raw = unsafe { ffi::wrenNewVM(&mut cfg.0) };
-        VM { raw, owned: true }
+        let context = Box::new(Context {
+            bindings: cfg.bindings,
+            user_data: cfg.raw.user_data,
+            reallocate_fn: cfg.raw.reallocate_fn,
+            panic: None,
+            error_fn: cfg.raw.error_fn,
+            reports: None,
+            nul_module: None,
+            output: OutputSink::new(cfg.output),
+            reporter: cfg.reporter,
+            data,
+            call_handles: HashMap::new(),
+            call_handle_stats: CallHandleStats::default(),
+            handles: Rc::new(HandleRegistry::new(cfg.track_handles)),
+        });
+        cfg.raw.user_data = Box::into_raw(context) as Pointer;
+        cfg.raw.error_fn = Some(report_error);
+        cfg.raw.reallocate_fn = Some(reallocate);
+        let raw = unsafe { ffi::wrenNewVM(&mut cfg.raw) };
+        let mut vm = VM { raw, owned: true };
+        vm.context().handles.vm.set(raw);
+        vm
     }
 
     /// Create a wrapper around an existing WrenVM pointer.
     ///
     /// This is mainly used by function wrapping macros.
+    /// The pointer must belong to a VM created with `VM::new`.
     pub unsafe fn from_ptr(ptr: *mut ffi::WrenVM) -> VM {
         VM {
             raw: ptr,
@@ -185,25 +548,162 @@ impl VM {
         }
     }
 
+    pub(crate) fn context(&mut self) -> &mut Context {
+        unsafe { &mut *(ffi::wrenGetUserData(self.raw) as *mut Context) }
+    }
+
+    /// Take the output collected since the last call, if the output is `Output::Buffer`.
+    ///
+    /// Invalid UTF-8 is replaced with `U+FFFD`.
+    pub fn take_output(&mut self) -> String {
+        let output = self.context().output.take();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// Take the payload of a panic caught in a callback, if any.
+    ///
+    /// Panics can't unwind through Wren, so the wrapped callbacks catch them and store the first
+    /// payload here. A foreign method that panics also aborts its fiber with the panic message.
+    /// Use `std::panic::resume_unwind` to re-raise the panic after `interpret` or `call` returns.
+    pub fn take_panic(&mut self) -> Option<Box<dyn Any + Send>> {
+        self.context().panic.take()
+    }
+
+    // Allocate memory for Wren to free, with the function it will free it with.
+    pub(crate) unsafe fn allocate_for_wren(&mut self, size: usize) -> Pointer {
+        match self.context().reallocate_fn {
+            Some(reallocate) => reallocate(ptr::null_mut(), size, ffi::wrenGetUserData(self.raw)),
+            // Wren's default reallocate function uses `realloc` and `free`.
+            None => libc::malloc(size),
+        }
+    }
+
+    pub(crate) fn store_panic(&mut self, payload: Box<dyn Any + Send>) {
+        let context = self.context();
+        if context.panic.is_none() {
+            context.panic = Some(payload);
+        }
+    }
+
+    pub(crate) fn store_nul_module(&mut self, name: &str, source: String) {
+        let context = self.context();
+        if context.nul_module.is_none() {
+            context.nul_module = Some((name.to_string(), source));
+        }
+    }
+
+    // Wren reports a module whose source contains a NUL byte as missing, so run `f` and return
+    // the module if that happened.
+    fn check_modules<R, F: FnOnce(&mut VM) -> R>(&mut self, f: F) -> (R, Option<(String, String)>) {
+        self.context().nul_module = None;
+        let result = f(self);
+        (result, self.context().nul_module.take())
+    }
+
+    // Run `f`, passing an imported module with a NUL byte on to the error function as a compile
+    // error of that module. Wren has already reported that it couldn't load it.
+    fn interpret_checked<F: FnOnce(&mut VM) -> InterpretResult>(
+        &mut self,
+        f: F,
+    ) -> InterpretResult {
+        let (result, nul_module) = self.check_modules(f);
+        if let Some((module, source)) = nul_module {
+            let position = source.find('\0').unwrap_or(0);
+            let line = source[..position].matches('\n').count() as c_int + 1;
+            let module = CString::new(module).unwrap();
+            let message = CString::new("Unexpected NUL byte.").unwrap();
+            unsafe {
+                report_error(
+                    self.raw,
+                    ErrorType::Compile,
+                    module.as_ptr(),
+                    line,
+                    message.as_ptr(),
+                )
+            };
+        }
+        result
+    }
+
     /// Maps to `wrenCollectGarbage`.
     pub fn collect_garbage(&mut self) {
         unsafe { ffi::wrenCollectGarbage(self.raw) }
     }
 
     /// Maps to `wrenInterpret`.
-    pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let source_cstr = CString::new(source).unwrap();
-        unsafe { ffi::wrenInterpret(self.raw, source_cstr.as_ptr()) }
+    ///
+    /// Returns `Error::NulByte` if the source contains a NUL byte. A NUL byte in the source of an
+    /// imported module is passed to the error function as a compile error of that module.
+    pub fn interpret(&mut self, source: &str) -> Result<InterpretResult, Error> {
+        let source_cstr = c_string(source)?;
+        let result = self
+            .interpret_checked(|vm| unsafe { ffi::wrenInterpret(vm.raw, source_cstr.as_ptr()) });
+        Ok(result)
     }
 
     /// Maps to `wrenInterpretInModule`.
-    pub fn interpret_in_module(&mut self, module: &str, source: &str) -> InterpretResult {
-        let module_cstr = CString::new(module).unwrap();
-        let source_cstr = CString::new(source).unwrap();
-        unsafe { ffi::wrenInterpretInModule(self.raw, module_cstr.as_ptr(), source_cstr.as_ptr()) }
+    ///
+    /// Returns `Error::NulByte` if the module name or the source contains a NUL byte. A NUL byte
+    /// in the source of an imported module is passed to the error function as a compile error of
+    /// that module.
+    pub fn interpret_in_module(
+        &mut self,
+        module: &str,
+        source: &str,
+    ) -> Result<InterpretResult, Error> {
+        let module_cstr = c_string(module)?;
+        let source_cstr = c_string(source)?;
+        let result = self.interpret_checked(|vm| unsafe {
+            ffi::wrenInterpretInModule(vm.raw, module_cstr.as_ptr(), source_cstr.as_ptr())
+        });
+        Ok(result)
+    }
+
+    /// Interpret `source` in the main module, returning the errors reported by Wren.
+    ///
+    /// Unlike `interpret`, errors are returned instead of being passed to the error function. A
+    /// NUL byte in the source, or in the source of an imported module, is returned as
+    /// `WrenError::NulByte` naming that module.
+    pub fn run(&mut self, source: &str) -> Result<(), WrenError> {
+        let module_cstr = CString::new("main").unwrap();
+        self.run_cstr(&module_cstr, "main", source)
+    }
+
+    /// Interpret `source` in `module`, returning the errors reported by Wren.
+    ///
+    /// Unlike `interpret_in_module`, errors are returned as `Error::Wren` instead of being passed
+    /// to the error function. Returns `Error::NulByte` if the module name contains a NUL byte. A
+    /// NUL byte in the source, or in the source of an imported module, is returned as
+    /// `WrenError::NulByte` naming that module, like `run` does.
+    pub fn run_in_module(&mut self, module: &str, source: &str) -> Result<(), Error> {
+        let module_cstr = c_string(module)?;
+        Ok(self.run_cstr(&module_cstr, module, source)?)
+    }
+
+    fn run_cstr(
+        &mut self,
+        module_cstr: &CStr,
+        module: &str,
+        source: &str,
+    ) -> Result<(), WrenError> {
+        let source_cstr = match CString::new(source) {
+            Ok(source_cstr) => source_cstr,
+            Err(_) => return Err(nul_byte_error(module, source)),
+        };
+        let run = |vm: &mut VM| {
+            vm.collect_reports(|vm| unsafe {
+                ffi::wrenInterpretInModule(vm.raw, module_cstr.as_ptr(), source_cstr.as_ptr())
+            })
+        };
+        match self.check_modules(run) {
+            (_, Some((module, source))) => Err(nul_byte_error(&module, &source)),
+            (result, None) => result,
+        }
     }
 
     /// Convenience function that loads a script from a file and interprets it.
+    ///
+    /// A NUL byte in the file is reported as `io::ErrorKind::InvalidData`.
     pub fn interpret_file(&mut self, path: &str) -> Result<InterpretResult, io::Error> {
         use std::fs::File;
         use std::io::Read;
@@ -211,30 +711,165 @@ impl VM {
         let mut buffer = String::new();
         let mut file = File::open(path)?;
         file.read_to_string(&mut buffer)?;
-        Ok(self.interpret(&buffer))
+        self.interpret(&buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
     /// Maps to `wrenMakeCallHandle`.
-    pub fn make_call_handle(&mut self, signature: &str) -> Handle {
-        let signature_cstr = CString::new(signature).unwrap();
-        let handle = RawHandle {
-            raw: unsafe { ffi::wrenMakeCallHandle(self.raw, signature_cstr.as_ptr()) },
-            vm: self.raw,
-        };
-        Handle(Rc::new(handle))
+    ///
+    /// Creates a new handle every time; use `call_handle` to reuse handles.
+    ///
+    /// Returns `Error::NulByte` if the signature contains a NUL byte.
+    #[track_caller]
+    pub fn make_call_handle(&mut self, signature: &str) -> Result<Handle, Error> {
+        let signature_cstr = c_string(signature)?;
+        let raw = unsafe { ffi::wrenMakeCallHandle(self.raw, signature_cstr.as_ptr()) };
+        Ok(self.new_handle(raw))
     }
 
     /// Maps to `wrenCall`.
+    ///
+    /// Panics if the handle belongs to another VM or has been released.
     pub fn call(&mut self, method: &Handle) -> InterpretResult {
-        unsafe { ffi::wrenCall(self.raw, method.0.raw) }
+        let method = self.handle_ptr(method);
+        unsafe { ffi::wrenCall(self.raw, method) }
+    }
+
+    /// Call a method like `call`, returning the runtime error reported by Wren.
+    ///
+    /// Errors are returned instead of being passed to the error function.
+    pub fn try_call(&mut self, method: &Handle) -> Result<(), WrenError> {
+        self.collect_reports(|vm| vm.call(method))
+    }
+
+    /// Call the method with `signature` on `receiver` and convert the result, e.g.
+    /// `vm.call_method(("main", "Math"), "add(_,_)", (1, 2))`.
+    ///
+    /// The call handle for each signature is created once and kept by the VM. Must not be used
+    /// inside foreign methods. Panics if the number of arguments doesn't match the signature.
+    #[track_caller]
+    pub fn call_method<T: Receiver, A: WrenArgs, R: FromWren>(
+        &mut self,
+        receiver: T,
+        signature: &str,
+        args: A,
+    ) -> Result<R, Error> {
+        assert!(
+            signature_arity(signature) == A::COUNT,
+            "Expected {} arguments for {}",
+            signature_arity(signature),
+            signature
+        );
+        let method = self.call_handle(signature)?;
+        self.call_with(receiver, &method, args)
+    }
+
+    /// Get the call handle for `signature`, creating it on first use.
+    ///
+    /// Handles are cached per VM, so calling this every frame is cheap.
+    ///
+    /// Returns `Error::NulByte` if the signature contains a NUL byte.
+    #[track_caller]
+    pub fn call_handle(&mut self, signature: &str) -> Result<Handle, Error> {
+        if let Some(handle) = self.context().call_handles.get(signature).cloned() {
+            self.context().call_handle_stats.hits += 1;
+            return Ok(handle);
+        }
+        let handle = self.make_call_handle(signature)?;
+        let context = self.context();
+        context.call_handle_stats.misses += 1;
+        context
+            .call_handles
+            .insert(signature.to_string(), handle.clone());
+        Ok(handle)
+    }
+
+    /// How often `call_handle` found a cached handle or had to create one.
+    pub fn call_handle_stats(&mut self) -> CallHandleStats {
+        self.context().call_handle_stats
+    }
+
+    // Call `method` on `receiver` with `args` and convert the result.
+    #[track_caller]
+    pub(crate) fn call_with<T: Receiver, A: WrenArgs, R: FromWren>(
+        &mut self,
+        receiver: T,
+        method: &Handle,
+        args: A,
+    ) -> Result<R, Error> {
+        let _origin = self.handle_origin();
+        self.ensure_slots(A::COUNT as i32 + 1);
+        receiver.set_receiver(self, 0)?;
+        args.set_args(self, 1);
+        self.try_call(method)?;
+        R::from_wren(self, 0)
+    }
+
+    // Run `f`, collecting the messages passed to the error function instead of forwarding them.
+    fn collect_reports<F: FnOnce(&mut VM) -> InterpretResult>(
+        &mut self,
+        f: F,
+    ) -> Result<(), WrenError> {
+        let previous = self.context().reports.replace(Vec::new());
+        let result = f(self);
+        let reports = mem::replace(&mut self.context().reports, previous);
+        WrenError::from_reports(result, reports.unwrap_or_default())
     }
 
-    /*
     /// Maps to `wrenReleaseHandle`.
+    ///
+    /// The handle is released even if it has been cloned, and using any of the clones afterwards
+    /// panics. Panics if the handle belongs to another VM.
+    ///
+    /// Handles returned by `call_handle` are shared by everything calling that signature, so
+    /// releasing one only drops this reference. The VM releases them when it's freed.
     pub fn release_handle(&mut self, handle: Handle) {
-        unsafe { ffi::wrenReleaseHandle(self.raw, handle.0.raw) }
+        self.check_handle(&handle);
+        let cached = self
+            .context()
+            .call_handles
+            .values()
+            .any(|cached| Rc::ptr_eq(&cached.0, &handle.0));
+        if !cached {
+            handle.0.release();
+        }
+    }
+
+    /// Where the live handles of this VM were created.
+    ///
+    /// Empty unless handle tracking was enabled with `Configuration::set_track_handles`. Handles
+    /// created by conversions and calls are recorded where the public function was called.
+    pub fn live_handles(&mut self) -> Vec<&'static Location<'static>> {
+        let registry = &self.context().handles;
+        let handles = registry.handles.borrow();
+        handles.values().filter_map(|&location| location).collect()
+    }
+
+    /// Free the VM like dropping it, returning where the handles still alive were created.
+    ///
+    /// The handles are released instead of logged. Empty unless handle tracking was enabled with
+    /// `Configuration::set_track_handles`. Panics if used on the VM passed to a callback.
+    pub fn free(mut self) -> Vec<&'static Location<'static>> {
+        assert!(self.owned, "Only the owner of a VM can free it");
+        self.free_owned()
+    }
+
+    fn free_owned(&mut self) -> Vec<&'static Location<'static>> {
+        if !self.owned {
+            return Vec::new();
+        }
+        self.owned = false;
+        unsafe {
+            // Finalizers may still use the context while the VM is freed.
+            let context = ffi::wrenGetUserData(self.raw) as *mut Context;
+            // Handles must be released before the VM is freed.
+            (*context).call_handles.clear();
+            let leaked = (*context).handles.release_all();
+            ffi::wrenFreeVM(self.raw);
+            drop(Box::from_raw(context));
+            leaked
+        }
     }
-    */
 
     /// Maps to `wrenGetSlotCount`.
     pub fn get_slot_count(&mut self) -> i32 {
@@ -296,7 +931,9 @@ impl VM {
     /// Returns `None` if the value in `slot` isn't a foreign object.
     pub fn get_slot_foreign(&mut self, slot: i32) -> Option<Pointer> {
         if self.get_slot_type(slot) == Type::Foreign {
-            Some(unsafe { ffi::wrenGetSlotForeign(self.raw, slot) })
+            Some(foreign::data(unsafe {
+                ffi::wrenGetSlotForeign(self.raw, slot)
+            }))
         } else {
             None
         }
@@ -304,40 +941,128 @@ impl VM {
 
     /// Convenience function that calls `wrenGetSlotForeign` and casts the result.
     ///
-    /// This function uses `mem::transmute` internally and is therefore very unsafe.
-    pub unsafe fn get_slot_foreign_typed<T>(&mut self, slot: i32) -> &mut T {
+    /// This function uses `mem::transmute` internally and is therefore very unsafe. Panics if the
+    /// object was created with `new_foreign` for a different type. Prefer `get_foreign`.
+    pub unsafe fn get_slot_foreign_typed<T: 'static>(&mut self, slot: i32) -> &mut T {
         assert!(
             self.get_slot_type(slot) == Type::Foreign,
             "Slot {} must contain a foreign object",
             slot
         );
-        mem::transmute::<Pointer, &mut T>(ffi::wrenGetSlotForeign(self.raw, slot))
+        let raw = ffi::wrenGetSlotForeign(self.raw, slot);
+        if let Some(type_id) = foreign::type_id(raw) {
+            assert!(
+                type_id == TypeId::of::<T>(),
+                "Slot {} must contain a foreign {}",
+                slot,
+                type_name::<T>()
+            );
+        }
+        mem::transmute::<Pointer, &mut T>(foreign::data(raw))
     }
 
-    /// Maps to `wrenGetSlotString`.
+    /// Get a reference to the value of a foreign object created with `new_foreign`.
     ///
-    /// Returns `None` if the value in `slot` isn't a string.
-    pub fn get_slot_string(&mut self, slot: i32) -> Option<&str> {
-        if self.get_slot_type(slot) == Type::String {
-            let ptr = unsafe { ffi::wrenGetSlotString(self.raw, slot) };
-            Some(unsafe { CStr::from_ptr(ptr).to_str().unwrap() })
-        } else {
-            None
+    /// Returns `None` if the value in `slot` isn't a foreign object holding a `T`.
+    pub fn get_foreign<T: 'static>(&mut self, slot: i32) -> Option<&mut T> {
+        if self.get_slot_type(slot) != Type::Foreign {
+            return None;
+        }
+        unsafe {
+            let raw = ffi::wrenGetSlotForeign(self.raw, slot);
+            if foreign::type_id(raw) == Some(TypeId::of::<T>()) {
+                Some(&mut *(foreign::data(raw) as *mut T))
+            } else {
+                None
+            }
         }
     }
 
+    /// Get the string in `slot`, replacing invalid UTF-8 with `U+FFFD`.
+    ///
+    /// Returns `None` if the value in `slot` isn't a string. Use `get_slot_bytes` for the exact
+    /// contents of strings built from arbitrary bytes.
+    pub fn get_slot_string(&mut self, slot: i32) -> Option<Cow<'_, str>> {
+        self.get_slot_bytes(slot).map(String::from_utf8_lossy)
+    }
+
     /// Maps to `wrenGetSlotHandle`.
+    #[track_caller]
     pub fn get_slot_handle(&mut self, slot: i32) -> Handle {
         assert!(
             self.get_slot_count() > slot,
             "Slot {} is out of bounds",
             slot
         );
-        let handle = RawHandle {
-            raw: unsafe { ffi::wrenGetSlotHandle(self.raw, slot) },
-            vm: self.raw,
-        };
-        Handle(Rc::new(handle))
+        let raw = unsafe { ffi::wrenGetSlotHandle(self.raw, slot) };
+        self.new_handle(raw)
+    }
+
+    #[track_caller]
+    fn new_handle(&mut self, raw: *mut ffi::WrenHandle) -> Handle {
+        let registry = self.context().handles.clone();
+        registry.insert(raw);
+        Handle(Rc::new(RawHandle {
+            raw: Cell::new(raw),
+            registry,
+        }))
+    }
+
+    #[track_caller]
+    fn handle_origin(&mut self) -> HandleOrigin {
+        let registry = &self.context().handles;
+        // An outer public function has already recorded its caller.
+        if !registry.track || registry.origin.get().is_some() {
+            return HandleOrigin(None);
+        }
+        registry.origin.set(Some(Location::caller()));
+        HandleOrigin(Some(registry.clone()))
+    }
+
+    fn check_handle(&mut self, handle: &Handle) {
+        assert!(
+            Rc::ptr_eq(&handle.0.registry, &self.context().handles),
+            "Handle belongs to a different VM"
+        );
+    }
+
+    // Get the pointer of a handle that can be used with this VM.
+    fn handle_ptr(&mut self, handle: &Handle) -> *mut ffi::WrenHandle {
+        self.check_handle(handle);
+        let raw = handle.0.raw.get();
+        assert!(!raw.is_null(), "Handle has been released");
+        raw
+    }
+
+    /// Copies the value in `slot` into a `Value`.
+    ///
+    /// Lists are copied recursively. Maps are returned as `Value::Unknown`, for the reason given
+    /// in the `FromWren` docs.
+    #[track_caller]
+    pub fn get_slot(&mut self, slot: i32) -> Value {
+        let _origin = self.handle_origin();
+        match self.get_slot_type(slot) {
+            Type::Bool => Value::Bool(self.get_slot_bool(slot).unwrap()),
+            Type::Num => Value::Num(self.get_slot_double(slot).unwrap()),
+            Type::Null => Value::Null,
+            Type::String => {
+                let bytes = self.get_slot_bytes(slot).unwrap().to_vec();
+                match String::from_utf8(bytes) {
+                    Ok(s) => Value::String(s),
+                    Err(e) => Value::Bytes(e.into_bytes()),
+                }
+            }
+            Type::List => Value::List(self.get_list_elements(slot, VM::get_slot)),
+            Type::Foreign => Value::Foreign(self.get_slot_handle(slot)),
+            Type::Map | Type::Unknown => Value::Unknown(self.get_slot_handle(slot)),
+        }
+    }
+
+    /// Converts the value in `slot` into a Rust value.
+    #[track_caller]
+    pub fn get<T: FromWren>(&mut self, slot: i32) -> Result<T, Error> {
+        let _origin = self.handle_origin();
+        T::from_wren(self, slot)
     }
 
     /// Maps to `wrenSetSlotBool`.
@@ -361,16 +1086,52 @@ impl VM {
     }
 
     /// Maps to `wrenSetSlotNewForeign`.
+    ///
+    /// The returned memory is uninitialized and isn't dropped by the default finalizer.
     pub fn set_slot_new_foreign(&mut self, slot: i32, class_slot: i32, size: usize) -> Pointer {
         self.ensure_slots(slot + 1);
-        unsafe { ffi::wrenSetSlotNewForeign(self.raw, slot, class_slot, size) }
+        self.check_foreign_class(class_slot);
+        unsafe {
+            let raw =
+                ffi::wrenSetSlotNewForeign(self.raw, slot, class_slot, foreign::HEADER_SIZE + size);
+            foreign::init_untyped(raw)
+        }
     }
 
     /// Convenience function that calls `wrenSetSlotNewForeign` using type information.
+    ///
+    /// The returned memory is uninitialized and isn't dropped by the default finalizer. Prefer
+    /// `new_foreign`.
     pub fn set_slot_new_foreign_typed<T>(&mut self, slot: i32, class_slot: i32) -> *mut T {
         self.set_slot_new_foreign(slot, class_slot, mem::size_of::<T>()) as *mut T
     }
 
+    /// Create a foreign object of the class in `class_slot` holding `value`.
+    ///
+    /// The value is dropped by the default finalizer of the class, and can be retrieved with
+    /// `get_foreign`. Panics if `T` needs an alignment of more than 8 bytes.
+    pub fn new_foreign<T: 'static>(&mut self, slot: i32, class_slot: i32, value: T) {
+        self.ensure_slots(slot + 1);
+        self.check_foreign_class(class_slot);
+        unsafe {
+            let raw = ffi::wrenSetSlotNewForeign(
+                self.raw,
+                slot,
+                class_slot,
+                foreign::HEADER_SIZE + mem::size_of::<T>(),
+            );
+            foreign::init_typed(raw, value);
+        }
+    }
+
+    fn check_foreign_class(&mut self, class_slot: i32) {
+        assert!(
+            self.get_slot_type(class_slot) == Type::Unknown,
+            "Slot {} must contain a foreign class",
+            class_slot
+        );
+    }
+
     /// Maps to `wrenSetSlotNewList`.
     pub fn set_slot_new_list(&mut self, slot: i32) {
         self.ensure_slots(slot + 1);
@@ -383,17 +1144,51 @@ impl VM {
         unsafe { ffi::wrenSetSlotNull(self.raw, slot) }
     }
 
-    /// Maps to `wrenSetSlotString`.
+    /// Set `slot` to a string, which may contain NUL bytes.
+    ///
+    /// This uses `wrenSetSlotBytes` rather than `wrenSetSlotString`, which stops at the first NUL.
     pub fn set_slot_string(&mut self, slot: i32, s: &str) {
-        self.ensure_slots(slot + 1);
-        let cstr = CString::new(s).unwrap();
-        unsafe { ffi::wrenSetSlotString(self.raw, slot, cstr.as_ptr()) }
+        self.set_slot_bytes(slot, s.as_bytes());
     }
 
     /// Maps to `wrenSetSlotHandle`.
+    ///
+    /// Panics if the handle belongs to another VM or has been released.
     pub fn set_slot_handle(&mut self, slot: i32, handle: &Handle) {
+        let handle = self.handle_ptr(handle);
         self.ensure_slots(slot + 1);
-        unsafe { ffi::wrenSetSlotHandle(self.raw, slot, handle.0.raw) }
+        unsafe { ffi::wrenSetSlotHandle(self.raw, slot, handle) }
+    }
+
+    /// Stores `value` in `slot`.
+    ///
+    /// Lists and maps are created recursively.
+    pub fn set_slot(&mut self, slot: i32, value: &Value) {
+        match *value {
+            Value::Null => self.set_slot_null(slot),
+            Value::Bool(b) => self.set_slot_bool(slot, b),
+            Value::Num(n) => self.set_slot_double(slot, n),
+            Value::String(ref s) => self.set_slot_string(slot, s),
+            Value::Bytes(ref bytes) => self.set_slot_bytes(slot, bytes),
+            Value::List(ref elements) => self.set_slot_new_list_from(slot, elements, VM::set_slot),
+            Value::Map(ref entries) => {
+                self.set_slot_new_map(slot);
+                let key_slot = self.get_slot_count();
+                for (key, value) in entries {
+                    self.set_slot(key_slot, key);
+                    self.set_slot(key_slot + 1, value);
+                    self.set_map_value(slot, key_slot, key_slot + 1);
+                }
+            }
+            Value::Foreign(ref handle) | Value::Unknown(ref handle) => {
+                self.set_slot_handle(slot, handle)
+            }
+        }
+    }
+
+    /// Converts `value` and stores it in `slot`.
+    pub fn set<T: ToWren>(&mut self, slot: i32, value: T) {
+        value.to_wren(self, slot)
     }
 
     /// Maps to `wrenGetListCount`.
@@ -408,29 +1203,40 @@ impl VM {
     // Checks parameters and converts a negative (relative) list index to an absolute index.
     // Wren already does the latter, but this way we can check if the index is out of bounds.
     // (which Wren doesn't do in release builds)
-    fn check_index(&mut self, list_slot: i32, index: i32) -> i32 {
+    //
+    // Insertion also accepts the index one past the last element, and counts negative indices
+    // from there (so -1 appends). Element access only accepts indices of existing elements.
+    fn check_index(&mut self, list_slot: i32, index: i32, insert: bool) -> i32 {
         assert!(
             self.get_slot_type(list_slot) == Type::List,
             "Slot {} must contain a list",
             list_slot
         );
         let list_count = self.get_list_count(list_slot);
-        let index = if index < 0 {
-            list_count + 1 + index
-        } else {
-            index
-        };
-        assert!(index <= list_count, "List index out of bounds");
+        let end = if insert { list_count + 1 } else { list_count };
+        let index = if index < 0 { end + index } else { index };
+        assert!(index >= 0 && index < end, "List index out of bounds");
         index
     }
 
     /// Maps to `wrenGetListElement`.
     pub fn get_list_element(&mut self, list_slot: i32, index: i32, element_slot: i32) {
         self.ensure_slots(element_slot + 1);
-        let index = self.check_index(list_slot, index);
+        let index = self.check_index(list_slot, index, false);
         unsafe { ffi::wrenGetListElement(self.raw, list_slot, index, element_slot) };
     }
 
+    /// Maps to `wrenSetListElement`.
+    pub fn set_list_element(&mut self, list_slot: i32, index: i32, element_slot: i32) {
+        assert!(
+            element_slot < self.get_slot_count(),
+            "No element in slot {}",
+            element_slot
+        );
+        let index = self.check_index(list_slot, index, false);
+        unsafe { ffi::wrenSetListElement(self.raw, list_slot, index, element_slot) };
+    }
+
     /// Maps to `wrenInsertInList`.
     pub fn insert_in_list(&mut self, list_slot: i32, index: i32, element_slot: i32) {
         assert!(
@@ -438,16 +1244,203 @@ impl VM {
             "No element in slot {}",
             element_slot
         );
-        let index = self.check_index(list_slot, index);
+        let index = self.check_index(list_slot, index, true);
         unsafe { ffi::wrenInsertInList(self.raw, list_slot, index, element_slot) };
     }
 
+    /// Removes the element at `index` from the list in `list_slot` and stores it in `removed_slot`.
+    ///
+    /// Wren has no slot function for this, so it calls `List.removeAt(_)` through `wrenCall`.
+    /// All other slots are preserved, but since this calls back into Wren it must not be used
+    /// inside a foreign method.
+    ///
+    /// Returns `Error::OutOfRange` if there's no element at `index`, in which case the slots are
+    /// left untouched.
+    pub fn remove_from_list(
+        &mut self,
+        list_slot: i32,
+        index: i32,
+        removed_slot: i32,
+    ) -> Result<(), Error> {
+        assert!(
+            self.get_slot_type(list_slot) == Type::List,
+            "Slot {} must contain a list",
+            list_slot
+        );
+        let count = self.get_list_count(list_slot);
+        let absolute = if index < 0 { count + index } else { index };
+        if absolute < 0 || absolute >= count {
+            return Err(Error::OutOfRange {
+                expected: "a list index",
+                value: index as f64,
+            });
+        }
+        let slots: Vec<Handle> = (0..self.get_slot_count())
+            .map(|slot| self.get_slot_handle(slot))
+            .collect();
+        let remove_at = self.call_handle("removeAt(_)")?;
+        self.set_slot_handle(0, &slots[list_slot as usize]);
+        self.set_slot_double(1, absolute as f64);
+        // The index has been checked, so `removeAt` can't abort. If it did, Wren would have
+        // discarded the slots, leaving no result to read and nothing to restore.
+        self.try_call(&remove_at)?;
+        let removed = self.get_slot_handle(0);
+        for (slot, handle) in slots.iter().enumerate() {
+            self.set_slot_handle(slot as i32, handle);
+        }
+        self.set_slot_handle(removed_slot, &removed);
+        Ok(())
+    }
+
+    /// Reads every element of the list in `list_slot` into a `Vec`.
+    ///
+    /// Each element is stored in a free slot, which is passed to `get` to read it.
+    #[track_caller]
+    pub fn get_list_elements<T, F>(&mut self, list_slot: i32, mut get: F) -> Vec<T>
+    where
+        F: FnMut(&mut VM, i32) -> T,
+    {
+        let _origin = self.handle_origin();
+        let count = self.get_list_count(list_slot);
+        let element_slot = self.get_slot_count();
+        (0..count)
+            .map(|index| {
+                self.get_list_element(list_slot, index, element_slot);
+                get(self, element_slot)
+            })
+            .collect()
+    }
+
+    /// Stores a new list in `slot` containing the given values.
+    ///
+    /// `set` is called to store each value in a free slot, from which it's added to the list.
+    pub fn set_slot_new_list_from<I, F>(&mut self, slot: i32, values: I, mut set: F)
+    where
+        I: IntoIterator,
+        F: FnMut(&mut VM, i32, I::Item),
+    {
+        self.set_slot_new_list(slot);
+        let element_slot = self.get_slot_count();
+        for value in values {
+            set(self, element_slot, value);
+            self.insert_in_list(slot, -1, element_slot);
+        }
+    }
+
+    /// Maps to `wrenSetSlotNewMap`.
+    pub fn set_slot_new_map(&mut self, slot: i32) {
+        self.ensure_slots(slot + 1);
+        unsafe { ffi::wrenSetSlotNewMap(self.raw, slot) }
+    }
+
+    /// Maps to `wrenGetMapCount`.
+    pub fn get_map_count(&mut self, slot: i32) -> i32 {
+        if self.get_slot_type(slot) == Type::Map {
+            unsafe { ffi::wrenGetMapCount(self.raw, slot) }
+        } else {
+            0
+        }
+    }
+
+    // Checks that `map_slot` contains a map and `key_slot` contains a value that can be used as a key.
+    // Only lists, maps and foreign objects can be ruled out here, since classes, ranges and other
+    // (unhashable) objects all share `Type::Unknown`.
+    fn check_map_key(&mut self, map_slot: i32, key_slot: i32) {
+        assert!(
+            self.get_slot_type(map_slot) == Type::Map,
+            "Slot {} must contain a map",
+            map_slot
+        );
+        assert!(
+            key_slot < self.get_slot_count(),
+            "No key in slot {}",
+            key_slot
+        );
+        match self.get_slot_type(key_slot) {
+            Type::List | Type::Map | Type::Foreign => {
+                panic!("Slot {} can't be used as a map key", key_slot)
+            }
+            _ => {}
+        }
+    }
+
+    /// Maps to `wrenGetMapContainsKey`.
+    pub fn get_map_contains_key(&mut self, map_slot: i32, key_slot: i32) -> bool {
+        self.check_map_key(map_slot, key_slot);
+        unsafe { ffi::wrenGetMapContainsKey(self.raw, map_slot, key_slot) }
+    }
+
+    /// Maps to `wrenGetMapValue`.
+    ///
+    /// If the key isn't in the map, `value_slot` is set to null.
+    pub fn get_map_value(&mut self, map_slot: i32, key_slot: i32, value_slot: i32) {
+        self.ensure_slots(value_slot + 1);
+        self.check_map_key(map_slot, key_slot);
+        unsafe { ffi::wrenGetMapValue(self.raw, map_slot, key_slot, value_slot) }
+    }
+
+    /// Maps to `wrenSetMapValue`.
+    pub fn set_map_value(&mut self, map_slot: i32, key_slot: i32, value_slot: i32) {
+        assert!(
+            value_slot < self.get_slot_count(),
+            "No value in slot {}",
+            value_slot
+        );
+        self.check_map_key(map_slot, key_slot);
+        unsafe { ffi::wrenSetMapValue(self.raw, map_slot, key_slot, value_slot) }
+    }
+
+    /// Maps to `wrenRemoveMapValue`.
+    ///
+    /// If the key isn't in the map, `removed_value_slot` is set to null.
+    pub fn remove_map_value(&mut self, map_slot: i32, key_slot: i32, removed_value_slot: i32) {
+        self.ensure_slots(removed_value_slot + 1);
+        self.check_map_key(map_slot, key_slot);
+        unsafe { ffi::wrenRemoveMapValue(self.raw, map_slot, key_slot, removed_value_slot) }
+    }
+
     /// Maps to `wrenGetVariable`.
-    pub fn get_variable(&mut self, module: &str, name: &str, slot: i32) {
+    ///
+    /// Returns an error if the module isn't loaded or doesn't define the variable, or
+    /// `Error::NulByte` if either name contains a NUL byte.
+    pub fn get_variable(&mut self, module: &str, name: &str, slot: i32) -> Result<(), Error> {
+        let module_cstr = c_string(module)?;
+        let name_cstr = c_string(name)?;
+        if !self.has_module(module) {
+            return Err(Error::UnknownModule(module.to_string()));
+        }
+        if !self.has_variable(module, name) {
+            return Err(Error::UnknownVariable {
+                module: module.to_string(),
+                name: name.to_string(),
+            });
+        }
         self.ensure_slots(slot + 1);
-        let module_cstr = CString::new(module).unwrap();
-        let name_cstr = CString::new(name).unwrap();
         unsafe { ffi::wrenGetVariable(self.raw, module_cstr.as_ptr(), name_cstr.as_ptr(), slot) }
+        Ok(())
+    }
+
+    /// Maps to `wrenHasVariable`.
+    ///
+    /// Returns `false` if the module isn't loaded.
+    pub fn has_variable(&mut self, module: &str, name: &str) -> bool {
+        if !self.has_module(module) {
+            return false;
+        }
+        match (c_string(module), c_string(name)) {
+            (Ok(module_cstr), Ok(name_cstr)) => unsafe {
+                ffi::wrenHasVariable(self.raw, module_cstr.as_ptr(), name_cstr.as_ptr())
+            },
+            _ => false,
+        }
+    }
+
+    /// Maps to `wrenHasModule`.
+    pub fn has_module(&mut self, module: &str) -> bool {
+        match c_string(module) {
+            Ok(module_cstr) => unsafe { ffi::wrenHasModule(self.raw, module_cstr.as_ptr()) },
+            Err(_) => false,
+        }
     }
 
     /// Maps to `wrenAbortFiber`.
@@ -455,21 +1448,34 @@ impl VM {
         unsafe { ffi::wrenAbortFiber(self.raw, slot) }
     }
 
-    /// Maps to `wrenGetUserData`.
+    /// Get the data passed to `new_with_data`.
+    ///
+    /// This also works in callbacks. Returns `None` if the VM has no data or it isn't a `T`.
+    pub fn data<T: 'static>(&mut self) -> Option<&mut T> {
+        match self.context().data {
+            Some(ref mut data) => data.downcast_mut(),
+            None => None,
+        }
+    }
+
+    /// Returns the data set with `set_user_data` or `Configuration::set_user_data`.
     pub fn get_user_data(&mut self) -> Pointer {
-        unsafe { ffi::wrenGetUserData(self.raw) }
+        self.context().user_data
     }
 
-    /// Maps to `wrenSetUserData`.
+    /// Sets the data returned by `get_user_data`.
     pub fn set_user_data(&mut self, data: Pointer) {
-        unsafe { ffi::wrenSetUserData(self.raw, data) }
+        self.context().user_data = data;
     }
 }
 
 impl Drop for VM {
     fn drop(&mut self) {
-        if self.owned {
-            unsafe { ffi::wrenFreeVM(self.raw) }
+        for location in self.free_owned() {
+            warn!(
+                "Handle created at {} was not released before freeing the VM",
+                location
+            );
         }
     }
 }
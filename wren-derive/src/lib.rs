@@ -0,0 +1,466 @@
+//! Procedural macros for the `wren` crate, enabled with its `derive` feature.
+//!
+//! `#[derive(WrenForeign)]` on a struct implements `wren::WrenForeign`, binding a getter and
+//! setter for each `pub` field. `#[wren_class]` on an `impl` block of the same type implements
+//! `wren::WrenClass`, binding the methods of the block together with an allocator and a
+//! finalizer for the type.
+//!
+//! ```ignore
+//! #[derive(WrenForeign)]
+//! pub struct Vec3 {
+//!     pub x: f64,
+//!     pub y: f64,
+//!     pub z: f64,
+//! }
+//!
+//! #[wren_class]
+//! impl Vec3 {
+//!     #[wren(construct)]
+//!     pub fn new(x: f64, y: f64, z: f64) -> Vec3 {
+//!         Vec3 { x, y, z }
+//!     }
+//!
+//!     pub fn dot(&self, rhs: &Vec3) -> f64 {
+//!         self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+//!     }
+//! }
+//!
+//! cfg.bindings_mut().add_foreign_class::<Vec3>("vector");
+//! cfg.bindings_mut().define_module("vector");
+//! ```
+//!
+//! The bindings record everything needed to generate the Wren declaration of the class, so
+//! `define_module` lets scripts import it without writing a `foreign class` by hand.
+//!
+//! The `wren` attribute controls how items are bound:
+//!
+//! * `#[wren(name = "...")]` renames a struct, field or method in Wren.
+//! * `#[wren(skip)]` leaves a field or method unbound.
+//! * `#[wren(construct)]` makes a method the allocator of the class. Without one, instances are
+//!   created with `Default::default()` by a constructor named `new()`.
+//! * `#[wren(getter)]` binds a method without arguments as a getter, e.g. `length`.
+//! * `#[wren(setter)]` binds a method with one argument as a setter, e.g. `length=(_)`. A `set_`
+//!   prefix is removed from the name.
+//!
+//! `pub` methods are bound, as are private ones with a `wren` attribute. Methods taking `self`
+//! by reference are instance methods, the others are static. Arguments are converted with
+//! `FromWren`, except for references to other types, which are read as foreign objects. Results
+//! are converted with `ToWren`, except for `Self`, which creates a new instance of the class.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, FnArg, ImplItem, ImplItemFn,
+    ItemImpl, LitStr, ReturnType, Type, Visibility,
+};
+
+#[proc_macro_derive(WrenForeign, attributes(wren))]
+pub fn derive_wren_foreign(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_foreign(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_attribute]
+pub fn wren_class(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        let args = TokenStream2::from(args);
+        return Error::new(args.span(), "wren_class takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let input = parse_macro_input!(input as ItemImpl);
+    expand_class(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The options of a `#[wren(...)]` attribute.
+#[derive(Default)]
+struct Options {
+    present: bool,
+    name: Option<String>,
+    skip: bool,
+    construct: bool,
+    getter: bool,
+    setter: bool,
+}
+
+fn parse_options(attrs: &[Attribute]) -> syn::Result<Options> {
+    let mut options = Options::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("wren")) {
+        options.present = true;
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                options.name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("skip") {
+                options.skip = true;
+            } else if meta.path.is_ident("construct") {
+                options.construct = true;
+            } else if meta.path.is_ident("getter") {
+                options.getter = true;
+            } else if meta.path.is_ident("setter") {
+                options.setter = true;
+            } else {
+                return Err(meta.error("unknown wren option"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(options)
+}
+
+fn expand_foreign(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "WrenForeign can't be derived for generic types",
+        ));
+    }
+    let options = parse_options(&input.attrs)?;
+    let name = options.name.unwrap_or_else(|| ident.to_string());
+
+    let mut accessors = Vec::new();
+    if let Data::Struct(data) = &input.data {
+        if let Fields::Named(fields) = &data.fields {
+            for field in &fields.named {
+                let options = parse_options(&field.attrs)?;
+                if options.skip || !matches!(field.vis, Visibility::Public(_)) {
+                    continue;
+                }
+                let field_ident = field.ident.as_ref().unwrap();
+                let ty = &field.ty;
+                let field_name = options.name.unwrap_or_else(|| field_ident.to_string());
+                let setter = format!("{}=(_)", field_name);
+                let error = format!("Invalid argument 1 for {}.{}: {{}}", name, setter);
+                accessors.push(quote! {
+                    bindings.add_method(module, #name, #field_name, false,
+                        ::wren::macros::_wrap_method(|vm: &mut ::wren::VM| {
+                            let this = ::wren::macros::_foreign::<Self>(vm, 0)?;
+                            ::wren::macros::_set_result(vm, unsafe { &(*this).#field_ident });
+                            Ok(())
+                        }));
+                    bindings.add_method(module, #name, #setter, false,
+                        ::wren::macros::_wrap_method(|vm: &mut ::wren::VM| {
+                            let this = ::wren::macros::_foreign::<Self>(vm, 0)?;
+                            let value = vm.get::<#ty>(1).map_err(|e| format!(#error, e))?;
+                            unsafe { (*this).#field_ident = value };
+                            Ok(())
+                        }));
+                });
+            }
+        }
+    } else {
+        return Err(Error::new(
+            Span::call_site(),
+            "WrenForeign can only be derived for structs",
+        ));
+    }
+
+    Ok(quote! {
+        impl ::wren::WrenForeign for #ident {
+            const NAME: &'static str = #name;
+
+            #[allow(unused_variables)]
+            fn bind_fields(module: &str, bindings: &mut ::wren::ForeignBindings) {
+                #(#accessors)*
+            }
+        }
+    })
+}
+
+/// Whether `ty` names the type of the impl block, either as `Self` or by its name.
+fn is_self_type(ty: &Type, self_ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) if path.qself.is_none() && path.path.is_ident("Self") => true,
+        Type::Paren(paren) => is_self_type(&paren.elem, self_ty),
+        _ => quote!(#ty).to_string() == quote!(#self_ty).to_string(),
+    }
+}
+
+/// A foreign object borrowed by a method, read into a pointer before any reference is created.
+struct Borrow {
+    ty: TokenStream2,
+    pointer: syn::Ident,
+    slot: i32,
+    mutable: bool,
+}
+
+impl Borrow {
+    fn new(ty: TokenStream2, slot: i32, mutable: bool) -> Borrow {
+        Borrow {
+            ty,
+            pointer: syn::Ident::new(&format!("__slot{}", slot), Span::call_site()),
+            slot,
+            mutable,
+        }
+    }
+
+    /// The statement reading the object into its pointer.
+    fn read(&self) -> TokenStream2 {
+        let Borrow {
+            ty, pointer, slot, ..
+        } = self;
+        quote! { let #pointer = ::wren::macros::_foreign::<#ty>(vm, #slot)?; }
+    }
+
+    /// The entry for this object in the `_check_aliasing` list.
+    fn object(&self) -> TokenStream2 {
+        let Borrow {
+            pointer,
+            slot,
+            mutable,
+            ..
+        } = self;
+        quote! { (#pointer as *const (), #slot, #mutable) }
+    }
+
+    fn reference(&self) -> TokenStream2 {
+        let pointer = &self.pointer;
+        if self.mutable {
+            quote! { unsafe { &mut *#pointer } }
+        } else {
+            quote! { unsafe { &*#pointer } }
+        }
+    }
+}
+
+/// Generate the expression reading argument `slot` of type `ty` in the method `signature`.
+/// Foreign objects taken by reference are added to `borrows`.
+fn argument(ty: &Type, slot: usize, signature: &str, borrows: &mut Vec<Borrow>) -> TokenStream2 {
+    let error = format!("Invalid argument {} for {}: {{}}", slot, signature);
+    let slot = slot as i32;
+    if let Type::Reference(reference) = ty {
+        let elem = &reference.elem;
+        if let Type::Path(path) = &**elem {
+            if path.path.is_ident("str") {
+                return quote! {
+                    &vm.get::<::std::string::String>(#slot).map_err(|e| format!(#error, e))?
+                };
+            }
+        }
+        let borrow = Borrow::new(quote!(#elem), slot, reference.mutability.is_some());
+        let reference = borrow.reference();
+        borrows.push(borrow);
+        return reference;
+    }
+    quote! { vm.get::<#ty>(#slot).map_err(|e| format!(#error, e))? }
+}
+
+struct Method {
+    ident: syn::Ident,
+    signature: String,
+    is_static: bool,
+    receiver: TokenStream2,
+    args: Vec<TokenStream2>,
+    returns_self: bool,
+    returns_ref: bool,
+    borrows: Vec<Borrow>,
+}
+
+fn method(
+    item: &ImplItemFn,
+    options: &Options,
+    class: &str,
+    self_ty: &Type,
+) -> syn::Result<Method> {
+    let ident = item.sig.ident.clone();
+    let mut name = options.name.clone().unwrap_or_else(|| ident.to_string());
+    if options.setter && options.name.is_none() && name.starts_with("set_") {
+        name = name["set_".len()..].to_string();
+    }
+
+    let mut receiver = TokenStream2::new();
+    let mut borrows = Vec::new();
+    let mut types = Vec::new();
+    for input in &item.sig.inputs {
+        match input {
+            FnArg::Receiver(r) if r.reference.is_none() => {
+                return Err(Error::new(
+                    r.span(),
+                    "foreign methods must take self by reference",
+                ));
+            }
+            FnArg::Receiver(r) => {
+                let borrow = Borrow::new(quote!(Self), 0, r.mutability.is_some());
+                let reference = borrow.reference();
+                receiver = quote! { #reference, };
+                borrows.push(borrow);
+            }
+            FnArg::Typed(arg) => types.push(&*arg.ty),
+        }
+    }
+    let is_static = receiver.is_empty();
+
+    let signature = if options.getter {
+        if !types.is_empty() {
+            return Err(Error::new(item.sig.span(), "getters take no arguments"));
+        }
+        name
+    } else if options.setter {
+        if types.len() != 1 {
+            return Err(Error::new(item.sig.span(), "setters take one argument"));
+        }
+        format!("{}=(_)", name)
+    } else {
+        let params = vec!["_"; types.len()];
+        format!("{}({})", name, params.join(","))
+    };
+
+    let full_name = format!("{}.{}", class, signature);
+    let args = types
+        .iter()
+        .enumerate()
+        .map(|(i, ty)| argument(ty, i + 1, &full_name, &mut borrows))
+        .collect();
+    let returns_self = match &item.sig.output {
+        ReturnType::Type(_, ty) => is_self_type(ty, self_ty),
+        ReturnType::Default => false,
+    };
+    let returns_ref = match &item.sig.output {
+        ReturnType::Type(_, ty) => matches!(**ty, Type::Reference(_)),
+        ReturnType::Default => false,
+    };
+
+    Ok(Method {
+        ident,
+        signature,
+        is_static,
+        receiver,
+        args,
+        returns_self,
+        returns_ref,
+        borrows,
+    })
+}
+
+fn expand_class(mut input: ItemImpl) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() || input.trait_.is_some() {
+        return Err(Error::new(
+            input.span(),
+            "wren_class can only be used on an inherent impl of a non-generic type",
+        ));
+    }
+    let self_ty = (*input.self_ty).clone();
+    let class = match &self_ty {
+        Type::Path(path) => path.path.segments.last().unwrap().ident.to_string(),
+        _ => {
+            return Err(Error::new(
+                self_ty.span(),
+                "unsupported type for wren_class",
+            ))
+        }
+    };
+
+    let mut constructor = None;
+    let mut methods = Vec::new();
+    for item in &mut input.items {
+        let item = match item {
+            ImplItem::Fn(item) => item,
+            _ => continue,
+        };
+        let options = parse_options(&item.attrs)?;
+        item.attrs.retain(|a| !a.path().is_ident("wren"));
+        let public = matches!(item.vis, Visibility::Public(_));
+        if options.skip || !(public || options.present) {
+            continue;
+        }
+        let method = method(item, &options, &class, &self_ty)?;
+        if options.construct {
+            if constructor.is_some() {
+                return Err(Error::new(
+                    item.sig.span(),
+                    "a foreign class can only have one constructor",
+                ));
+            }
+            if !method.is_static || !method.returns_self {
+                return Err(Error::new(
+                    item.sig.span(),
+                    "constructors must be static and return Self",
+                ));
+            }
+            constructor = Some(method);
+        } else {
+            methods.push(method);
+        }
+    }
+
+    let (allocate, constructor) = match constructor {
+        Some(Method {
+            ident,
+            signature,
+            args,
+            ..
+        }) => (quote! { Self::#ident(#(#args),*) }, signature),
+        None => (
+            quote! { <Self as ::std::default::Default>::default() },
+            "new()".to_string(),
+        ),
+    };
+
+    let shims = methods.iter().map(|method| {
+        let Method {
+            ident,
+            signature,
+            is_static,
+            receiver,
+            args,
+            returns_self,
+            returns_ref,
+            borrows,
+        } = method;
+        let reads = borrows.iter().map(Borrow::read);
+        // Wren passes objects by reference, so `a.merge(a)` hands the same object to two slots.
+        let check = if borrows.len() > 1 {
+            let full_name = format!("{}.{}", class, signature);
+            let objects = borrows.iter().map(Borrow::object);
+            quote! { ::wren::macros::_check_aliasing(#full_name, &[#(#objects),*])?; }
+        } else {
+            TokenStream2::new()
+        };
+        let store = if *returns_self {
+            quote! { ::wren::macros::_new_instance::<Self>(vm, 0, result)?; }
+        } else if *returns_ref {
+            // The result may borrow from the receiver in slot 0.
+            quote! { ::wren::macros::_set_result(vm, result); }
+        } else {
+            quote! { vm.set(0, result); }
+        };
+        quote! {
+            bindings.add_method(module, name, #signature, #is_static,
+                ::wren::macros::_wrap_method(|vm: &mut ::wren::VM| {
+                    #(#reads)*
+                    #check
+                    let result = Self::#ident(#receiver #(#args),*);
+                    #store
+                    Ok(())
+                }));
+        }
+    });
+
+    Ok(quote! {
+        #input
+
+        impl ::wren::WrenClass for #self_ty {
+            fn bind(module: &str, bindings: &mut ::wren::ForeignBindings) {
+                let name = <Self as ::wren::WrenForeign>::NAME;
+                let mut methods = ::wren::ForeignClassMethods::new();
+                methods.set_allocate_fn(::wren::macros::_wrap_method(|vm: &mut ::wren::VM| {
+                    let value = #allocate;
+                    ::wren::macros::_allocate::<Self>(vm, value);
+                    Ok(())
+                }));
+                bindings.add_class(module, name, methods);
+                bindings.add_constructor(module, name, #constructor);
+                <Self as ::wren::WrenForeign>::bind_fields(module, bindings);
+                #(#shims)*
+            }
+        }
+    })
+}
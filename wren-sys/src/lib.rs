@@ -114,6 +114,7 @@ pub enum WrenType {
     Num,
     Foreign,
     List,
+    Map,
     Null,
     String,
     Unknown,